@@ -0,0 +1,21 @@
+//! Prints the webhook events the bot sends, to try a `[[webhooks]]` config locally.
+//!
+//! `cargo run --example webhook_receiver [127.0.0.1:8787]`, then use
+//! `url = "http://127.0.0.1:8787/"` in the config file.
+
+fn main() {
+    let addr = std::env::args().nth(1).unwrap_or_else(|| "127.0.0.1:8787".to_owned());
+    let server = tiny_http::Server::http(&addr).expect("failed to listen");
+    println!("listening on http://{}/", addr);
+    for mut req in server.incoming_requests() {
+        let mut body = String::new();
+        if let Err(err) = req.as_reader().read_to_string(&mut body) {
+            eprintln!("failed to read the request: {}", err);
+        }
+        match serde_json::from_str::<serde_json::Value>(&body) {
+            Ok(event) => println!("{}", serde_json::to_string_pretty(&event).unwrap()),
+            Err(_) => println!("{} {}: {}", req.method(), req.url(), body),
+        }
+        let _ = req.respond(tiny_http::Response::empty(200));
+    }
+}
@@ -0,0 +1,443 @@
+//! Local control api, line-delimited json on a unix socket (`--api-socket`).
+//!
+//! Every request is one line, `{"id": 1, "method": "post", "params": {"text": "hi", "to": "members"}}`,
+//! and gets one response line with the same id, `{"id": 1, "result": true}` or `{"id": 1, "error": "..."}`.
+//! After `subscribe`, events are written as they happen, `{"event": "message", "data": {...}}`.
+use std::collections::HashSet;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use bhcli::lechatphp::{
+    get_message, Message, MessageType, Users, SEND_TO_ADMINS, SEND_TO_ALL, SEND_TO_MEMBERS, SEND_TO_STAFFS,
+};
+use crossbeam_channel::{Sender, TrySendError};
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+
+use crate::audit::{AuditEntry, AuditKind};
+use crate::PostType;
+
+/// Lines waiting to be written to a client, events are dropped when it does not keep up.
+const CLIENT_BUFFER: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    Messages,
+    Users,
+    Moderation,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case", deny_unknown_fields)]
+enum Call {
+    Post {
+        text: String,
+        /// `all` (default), `members`, `staff`, `admins` or a nickname
+        #[serde(default)]
+        to: Option<String>,
+    },
+    Kick {
+        user: String,
+        #[serde(default)]
+        text: String,
+    },
+    Upload {
+        path: String,
+        #[serde(default)]
+        to: Option<String>,
+        #[serde(default)]
+        text: String,
+    },
+    Delete {
+        date: String,
+        text: String,
+    },
+    DeleteLast {},
+    DeleteAll {},
+    Nickname {
+        nickname: String,
+    },
+    Color {
+        color: String,
+    },
+    Ignore {
+        user: String,
+    },
+    Unignore {
+        user: String,
+    },
+    Inbox {},
+    CleanInbox {},
+    Messages {
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    Users {},
+    Subscribe {
+        topics: Vec<Topic>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Option<Value>,
+}
+
+struct Subscriber {
+    conn: usize,
+    topics: HashSet<Topic>,
+    out: Sender<String>,
+}
+
+/// What the running session shares with the api clients.
+#[derive(Default)]
+pub struct Api {
+    subscribers: Mutex<Vec<Subscriber>>,
+    next_conn: AtomicUsize,
+    users: Mutex<Value>,
+}
+
+// Handles of the session the requests act on
+struct Session {
+    tx: Sender<PostType>,
+    messages: Arc<Mutex<Vec<Message>>>,
+    members_tag: String,
+}
+
+impl Api {
+    /// Listen on `path` in the background, a stale socket is replaced but not any other file.
+    #[cfg(unix)]
+    pub fn serve(
+        &'static self,
+        path: &Path,
+        tx: Sender<PostType>,
+        messages: Arc<Mutex<Vec<Message>>>,
+        members_tag: &str,
+    ) -> io::Result<()> {
+        let listener = bind_private(path)?;
+        let session = Arc::new(Session {
+            tx,
+            messages,
+            members_tag: members_tag.to_owned(),
+        });
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::error!("api: {}", err);
+                        continue;
+                    }
+                };
+                let Ok(writer) = stream.try_clone() else {
+                    continue;
+                };
+                let session = Arc::clone(&session);
+                thread::spawn(move || self.handle_client(BufReader::new(stream), writer, &session));
+            }
+        });
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn serve(&'static self, _: &Path, _: Sender<PostType>, _: Arc<Mutex<Vec<Message>>>, _: &str) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "the api needs unix sockets"))
+    }
+
+    fn handle_client(&self, reader: impl BufRead, mut writer: impl Write + Send + 'static, session: &Session) {
+        let conn = self.next_conn.fetch_add(1, Ordering::Relaxed);
+        let (out, out_rx) = crossbeam_channel::bounded::<String>(CLIENT_BUFFER);
+        let handle = thread::spawn(move || {
+            for line in out_rx {
+                if writeln!(writer, "{}", line).is_err() {
+                    break;
+                }
+            }
+        });
+        for line in reader.lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = self.handle_line(conn, &line, &out, session);
+            if out.send(response.to_string()).is_err() {
+                break;
+            }
+        }
+        self.subscribers.lock().unwrap().retain(|s| s.conn != conn);
+        drop(out);
+        let _ = handle.join();
+    }
+
+    fn handle_line(&self, conn: usize, line: &str, out: &Sender<String>, session: &Session) -> Value {
+        let req: Request = match serde_json::from_str(line) {
+            Ok(req) => req,
+            Err(err) => return json!({"id": null, "error": format!("invalid request: {}", err)}),
+        };
+        let call = json!({"method": req.method, "params": req.params.unwrap_or_else(|| json!({}))});
+        match serde_json::from_value(call) {
+            Ok(call) => match self.call(conn, call, out, session) {
+                Ok(result) => json!({"id": req.id, "result": result}),
+                Err(err) => json!({"id": req.id, "error": err}),
+            },
+            Err(err) => json!({"id": req.id, "error": err.to_string()}),
+        }
+    }
+
+    fn call(&self, conn: usize, call: Call, out: &Sender<String>, session: &Session) -> Result<Value, String> {
+        let post = match call {
+            Call::Post { text, to } => PostType::Post(text, Some(send_to(to.as_deref()))),
+            Call::Kick { user, text } => {
+                let actor = format!("api:{}", conn);
+                crate::audit(AuditEntry::new(AuditKind::Kick, &user.to_lowercase(), "manual", &text, "", &actor));
+                PostType::Kick(text, user)
+            }
+            Call::Upload { path, to, text } => PostType::Upload(path, send_to(to.as_deref()), text),
+            Call::Delete { date, text } => PostType::Clean(date, text),
+            Call::DeleteLast {} => PostType::DeleteLast,
+            Call::DeleteAll {} => PostType::DeleteAll,
+            Call::Nickname { nickname } => PostType::NewNickname(nickname),
+            Call::Color { color } => PostType::NewColor(color),
+            Call::Ignore { user } => PostType::Ignore(user),
+            Call::Unignore { user } => PostType::Unignore(user),
+            Call::Inbox {} => PostType::Inbox,
+            Call::CleanInbox {} => PostType::InboxClean,
+            Call::Messages { limit } => {
+                let messages = session.messages.lock().unwrap();
+                let messages = messages.iter().take(limit.unwrap_or(usize::MAX));
+                return Ok(messages.map(|m| message_json(m, &session.members_tag)).collect());
+            }
+            Call::Users {} => return Ok(self.users.lock().unwrap().clone()),
+            Call::Subscribe { topics } => {
+                let mut subscribers = self.subscribers.lock().unwrap();
+                subscribers.retain(|s| s.conn != conn);
+                subscribers.push(Subscriber {
+                    conn,
+                    topics: topics.into_iter().collect(),
+                    out: out.clone(),
+                });
+                return Ok(Value::Bool(true));
+            }
+        };
+        // Sent by the post thread like everything typed in the TUI
+        session.tx.send(post).map_err(|_| "not connected".to_owned())?;
+        Ok(Value::Bool(true))
+    }
+
+    pub fn publish(&self, topic: Topic, event: &str, data: Value) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        let line = json!({"event": event, "data": data}).to_string();
+        subscribers.retain(|s| {
+            if !s.topics.contains(&topic) {
+                return true;
+            }
+            match s.out.try_send(line.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("api: client {} is too slow, event dropped", s.conn);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    pub fn message(&self, msg: &Message, members_tag: &str) {
+        self.publish(Topic::Messages, "message", message_json(msg, members_tag));
+    }
+
+    /// Remember the users online, subscribers are told when the list changes.
+    pub fn users(&self, users: &Users) {
+        let names = |list: &[(tui::style::Color, String)]| list.iter().map(|(_, n)| n.clone()).collect::<Vec<_>>();
+        let users = json!({
+            "admins": names(&users.admin),
+            "staff": names(&users.staff),
+            "members": names(&users.members),
+            "guests": names(&users.guests),
+        });
+        let mut last = self.users.lock().unwrap();
+        if *last != users {
+            *last = users.clone();
+            drop(last);
+            self.publish(Topic::Users, "users", users);
+        }
+    }
+
+    pub fn moderation(&self, entry: &AuditEntry) {
+        self.publish(Topic::Moderation, "moderation", entry.to_json());
+    }
+}
+
+fn send_to(to: Option<&str>) -> String {
+    match to {
+        None | Some("all") => SEND_TO_ALL,
+        Some("members") => SEND_TO_MEMBERS,
+        Some("staff") => SEND_TO_STAFFS,
+        Some("admins") => SEND_TO_ADMINS,
+        Some(nickname) => nickname,
+    }
+    .to_owned()
+}
+
+pub fn message_json(m: &Message, members_tag: &str) -> Value {
+    let (from, to, text) = match get_message(&m.text, members_tag) {
+        Some((from, to, text)) => (Some(from), to, text),
+        None => (None, None, m.text.text()),
+    };
+    json!({
+        "id": m.id,
+        "date": m.date,
+        "system": m.typ == MessageType::SysMsg,
+        "from": from,
+        "to": to,
+        "members": m.text.text().starts_with(members_tag),
+        "text": text,
+        "upload_link": m.upload_link,
+        "deleted": m.deleted,
+        "hidden": m.hide,
+    })
+}
+
+// Whoever can connect can kick, so only the owner can. The socket is bound in a directory
+// only we can enter and moved in place once its permissions are set.
+#[cfg(unix)]
+fn bind_private(path: &Path) -> io::Result<std::os::unix::net::UnixListener> {
+    use std::fs;
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    use std::os::unix::net::UnixListener;
+
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            let msg = format!("{} exists and is not a socket", path.display());
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let Some(name) = path.file_name() else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the api socket needs a file name"));
+    };
+    let private = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let tmp = private.join("api.sock");
+    let listener = UnixListener::bind(&tmp).and_then(|listener| {
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&tmp, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&tmp);
+    let _ = fs::remove_dir(&private);
+    listener
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use bhcli::lechatphp::StyledText;
+
+    fn session() -> (Session, crossbeam_channel::Receiver<PostType>) {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let msg = Message::new(
+            Some(1),
+            MessageType::SysMsg,
+            "01-01 10:00:00".to_owned(),
+            None,
+            StyledText::Text("bob has joined the chat.".to_owned()),
+        );
+        let session = Session {
+            tx,
+            messages: Arc::new(Mutex::new(vec![msg])),
+            members_tag: "[M] ".to_owned(),
+        };
+        (session, rx)
+    }
+
+    #[test]
+    fn socket_only_for_the_owner() {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        let dir = std::env::temp_dir().join(format!("bhcli-api-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("api.sock");
+        let _stale = bind_private(&path).unwrap();
+        // A stale socket is replaced
+        let _listener = bind_private(&path).unwrap();
+        let meta = std::fs::symlink_metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        assert!(std::os::unix::net::UnixStream::connect(&path).is_ok());
+        // Nothing left behind
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        let file = dir.join("notes.txt");
+        std::fs::write(&file, "keep me").unwrap();
+        assert_eq!(bind_private(&file).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep me");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn requests_and_events() {
+        let api: &'static Api = Box::leak(Box::default());
+        let (session, posts) = session();
+        let requests = [
+            r#"{"id": 1, "method": "post", "params": {"text": "hi", "to": "members"}}"#,
+            r#"{"id": 2, "method": "kick", "params": {"user": "spammer"}}"#,
+            r#"{"id": 3, "method": "delete_last"}"#,
+            r#"{"id": 4, "method": "messages", "params": {"limit": 5}}"#,
+            r#"{"id": 5, "method": "subscribe", "params": {"topics": ["users"]}}"#,
+            r#"{"id": 6, "method": "dance"}"#,
+            "not json",
+        ];
+        let (mut client, server) = std::os::unix::net::UnixStream::pair().unwrap();
+        for r in requests {
+            writeln!(client, "{}", r).unwrap();
+        }
+        let writer = server.try_clone().unwrap();
+        let handle = thread::spawn(move || api.handle_client(BufReader::new(server), writer, &session));
+
+        let mut lines = BufReader::new(client.try_clone().unwrap())
+            .lines()
+            .map(|l| serde_json::from_str::<Value>(&l.unwrap()).unwrap());
+        let responses: Vec<Value> = lines.by_ref().take(requests.len()).collect();
+        assert_eq!(responses[0], json!({"id": 1, "result": true}));
+        assert_eq!(responses[3]["result"][0]["text"], "bob has joined the chat.");
+        assert_eq!(responses[3]["result"][0]["system"], true);
+        assert!(responses[5]["error"].as_str().unwrap().contains("dance"));
+        assert!(responses[6]["error"].as_str().unwrap().starts_with("invalid request"));
+
+        let posts: Vec<String> = posts.try_iter().map(|p| format!("{:?}", p)).collect();
+        assert_eq!(posts, vec![r#"Post("hi", Some("s ?"))"#, r#"Kick("", "spammer")"#, "DeleteLast"]);
+        let kicks = crate::AUDIT.lock().unwrap().kicks(100).unwrap();
+        assert!(kicks.iter().any(|k| k.target == "spammer" && k.rule == "manual" && k.actor.starts_with("api:")));
+
+        let users = Users {
+            guests: vec![(tui::style::Color::White, "bob".to_owned())],
+            ..Default::default()
+        };
+        api.users(&users);
+        // Unchanged, not published again
+        api.users(&users);
+        api.moderation(&AuditEntry::new(AuditKind::Kick, "bob", "manual", "", "", "me"));
+        let event = lines.next().unwrap();
+        assert_eq!(event, json!({"event": "users", "data": {"admins": [], "staff": [], "members": [], "guests": ["bob"]}}));
+        drop(client);
+        drop(lines);
+        handle.join().unwrap();
+        assert!(api.subscribers.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+/// Appended to the last post when an answer has too many posts.
+const TRUNCATED: &str = "\n[...]";
+const FENCE: &str = "```";
+
+/// Splits long answers into posts the chat accepts, at line then sentence boundaries.
+/// Code blocks are kept in one post when they fit, otherwise every part is fenced again.
+#[derive(Debug, Clone, Copy)]
+pub struct Chunker {
+    /// Longest message the server accepts, in characters
+    pub max_length: usize,
+    /// Posts per answer, the rest is dropped, 0 for no limit
+    pub max_chunks: usize,
+}
+
+impl Chunker {
+    pub fn split(&self, text: &str) -> Vec<String> {
+        let max = self.max_length.max(2 * FENCE.len() + TRUNCATED.len() + 10);
+        let mut chunks = vec![];
+        let mut current = String::new();
+        for (sep, piece) in pieces(text, max) {
+            if current.is_empty() {
+                current = piece;
+            } else if len(&current) + len(sep) + len(&piece) <= max {
+                current += sep;
+                current += &piece;
+            } else {
+                chunks.push(std::mem::replace(&mut current, piece));
+            }
+        }
+        if !current.trim().is_empty() {
+            chunks.push(current);
+        }
+        if self.max_chunks > 0 && chunks.len() > self.max_chunks {
+            chunks.truncate(self.max_chunks);
+            let last = chunks.last_mut().unwrap();
+            while len(last) + len(TRUNCATED) > max {
+                last.pop();
+            }
+            *last += TRUNCATED;
+        }
+        chunks
+    }
+}
+
+fn len(s: &str) -> usize {
+    s.chars().count()
+}
+
+// Lines, code blocks and sentences of too long lines, each with what joins it to the previous one
+fn pieces(text: &str, max: usize) -> Vec<(&'static str, String)> {
+    let mut pieces = vec![];
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        if line.trim_start().starts_with(FENCE) {
+            let mut block = vec![line];
+            for line in lines.by_ref() {
+                block.push(line);
+                if line.trim_start().starts_with(FENCE) {
+                    break;
+                }
+            }
+            pieces.extend(code_block(&block, max).into_iter().map(|p| ("\n", p)));
+        } else if len(line) <= max {
+            pieces.push(("\n", line.to_owned()));
+        } else {
+            let mut sep = "\n";
+            for sentence in sentences(line) {
+                for part in hard_split(sentence, max) {
+                    pieces.push((sep, part));
+                    sep = " ";
+                }
+            }
+        }
+    }
+    pieces
+}
+
+fn code_block(block: &[&str], max: usize) -> Vec<String> {
+    let whole = block.join("\n");
+    if len(&whole) <= max {
+        return vec![whole];
+    }
+    let open = block[0];
+    let body = match block.last() {
+        Some(last) if block.len() > 1 && last.trim_start().starts_with(FENCE) => &block[1..block.len() - 1],
+        _ => &block[1..],
+    };
+    let room = max.saturating_sub(len(open) + FENCE.len() + 2).max(1);
+    let mut parts = vec![];
+    let mut current = String::new();
+    for line in body.iter().flat_map(|line| hard_split(line, room)) {
+        if !current.is_empty() && len(&current) + 1 + len(&line) > room {
+            parts.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current += &line;
+    }
+    parts.push(current);
+    parts.into_iter().map(|part| format!("{}\n{}\n{}", open, part, FENCE)).collect()
+}
+
+fn sentences(line: &str) -> Vec<&str> {
+    let mut sentences = vec![];
+    let mut start = 0;
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if matches!(c, '.' | '!' | '?') && chars.peek().is_some_and(|(_, next)| next.is_whitespace()) {
+            sentences.push(line[start..i + 1].trim());
+            start = i + 1;
+        }
+    }
+    sentences.push(line[start..].trim());
+    sentences.retain(|s| !s.is_empty());
+    sentences
+}
+
+// Cut at the last whitespace that fits, anywhere if there is none
+fn hard_split(s: &str, max: usize) -> Vec<String> {
+    let mut parts = vec![];
+    let mut rest = s;
+    while len(rest) > max {
+        let cut = rest.char_indices().nth(max).map(|(i, _)| i).unwrap();
+        let at = match rest[..cut].rfind(char::is_whitespace) {
+            Some(at) if at > 0 => at,
+            _ => cut,
+        };
+        parts.push(rest[..at].trim_end().to_owned());
+        rest = rest[at..].trim_start();
+    }
+    parts.push(rest.to_owned());
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_answers() {
+        let chunker = Chunker {
+            max_length: 40,
+            max_chunks: 0,
+        };
+        assert_eq!(chunker.split("short answer"), vec!["short answer"]);
+        assert_eq!(
+            chunker.split("First sentence is here. Second one is here too! And a third?"),
+            vec!["First sentence is here.", "Second one is here too! And a third?"]
+        );
+        assert_eq!(chunker.split("line one\nline two\n\nline three"), vec!["line one\nline two\n\nline three"]);
+
+        let code = "Try this:\n```rust\nfn main() {\n    println!(\"hi\");\n}\n```\ndone";
+        let chunks = chunker.split(code);
+        assert_eq!(
+            chunks,
+            vec!["Try this:\n```rust\nfn main() {\n```", "```rust\n    println!(\"hi\");\n}\n```\ndone"]
+        );
+
+        let chunker = Chunker {
+            max_length: 100,
+            max_chunks: 0,
+        };
+        assert_eq!(chunker.split(code), vec![code]);
+
+        let long = "word ".repeat(100);
+        let chunker = Chunker {
+            max_length: 50,
+            max_chunks: 2,
+        };
+        let chunks = chunker.split(&long);
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(|c| len(c) <= 50));
+        assert!(chunks[1].ends_with(TRUNCATED));
+    }
+}
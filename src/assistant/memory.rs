@@ -0,0 +1,119 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+/// One message of a conversation with the assistant.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Turn {
+    pub role: Role,
+    pub text: String,
+}
+
+impl Turn {
+    pub fn user(text: &str) -> Self {
+        Turn {
+            role: Role::User,
+            text: text.to_owned(),
+        }
+    }
+
+    pub fn assistant(text: &str) -> Self {
+        Turn {
+            role: Role::Assistant,
+            text: text.to_owned(),
+        }
+    }
+}
+
+struct Conversation {
+    turns: VecDeque<Turn>,
+    last_used: Instant,
+}
+
+/// Recent conversations, one per user and channel, bounded in length and forgotten when idle.
+pub struct Memory {
+    max_turns: usize,
+    idle: Duration,
+    conversations: HashMap<(String, String), Conversation>,
+}
+
+impl Memory {
+    pub fn new(max_turns: usize, idle: Duration) -> Self {
+        Memory {
+            max_turns,
+            idle,
+            conversations: HashMap::new(),
+        }
+    }
+
+    /// Previous turns of `user` in `channel`, oldest first.
+    pub fn turns(&mut self, user: &str, channel: &str, now: Instant) -> Vec<Turn> {
+        let idle = self.idle;
+        self.conversations.retain(|_, c| now.duration_since(c.last_used) <= idle);
+        self.conversations
+            .get(&key(user, channel))
+            .map(|c| c.turns.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn push(&mut self, user: &str, channel: &str, question: &str, answer: &str, now: Instant) {
+        if self.max_turns == 0 {
+            return;
+        }
+        let conversation = self.conversations.entry(key(user, channel)).or_insert(Conversation {
+            turns: VecDeque::new(),
+            last_used: now,
+        });
+        conversation.last_used = now;
+        conversation.turns.push_back(Turn::user(question));
+        conversation.turns.push_back(Turn::assistant(answer));
+        while conversation.turns.len() > self.max_turns {
+            conversation.turns.pop_front();
+        }
+    }
+
+    /// Forget every conversation of `user`, returns false if there was none.
+    pub fn reset(&mut self, user: &str) -> bool {
+        let user = user.to_lowercase();
+        let before = self.conversations.len();
+        self.conversations.retain(|(u, _), _| *u != user);
+        before != self.conversations.len()
+    }
+}
+
+fn key(user: &str, channel: &str) -> (String, String) {
+    (user.to_lowercase(), channel.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_per_user_and_channel() {
+        let mut memory = Memory::new(4, Duration::from_secs(60));
+        let now = Instant::now();
+        memory.push("Bob", "public", "q1", "a1", now);
+        memory.push("bob", "public", "q2", "a2", now);
+        memory.push("bob", "public", "q3", "a3", now);
+        memory.push("bob", "pm", "secret", "ok", now);
+        assert_eq!(
+            memory.turns("BOB", "public", now),
+            vec![Turn::user("q2"), Turn::assistant("a2"), Turn::user("q3"), Turn::assistant("a3")]
+        );
+        assert_eq!(memory.turns("bob", "pm", now).len(), 2);
+        assert!(memory.turns("alice", "public", now).is_empty());
+
+        assert!(memory.reset("Bob"));
+        assert!(!memory.reset("bob"));
+        assert!(memory.turns("bob", "pm", now).is_empty());
+
+        memory.push("bob", "public", "q", "a", now);
+        assert!(memory.turns("bob", "public", now + Duration::from_secs(61)).is_empty());
+    }
+}
@@ -0,0 +1,475 @@
+use std::env;
+use std::error;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+use bhcli::lechatphp::Users;
+use reqwest::blocking::Client;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+pub use self::chunks::Chunker;
+pub use self::memory::{Memory, Role, Turn};
+pub use self::worker::{Question, Rejected, Reply, Worker};
+
+mod chunks;
+mod memory;
+mod worker;
+
+/// Prompt used when none is configured, `{members}`, `{staff}` and `{admins}` are replaced
+/// by the nicknames online.
+pub const DEFAULT_SYSTEM_PROMPT: &str = include_str!("system_prompt.txt");
+
+const GEMINI_MODEL: &str = "gemini-1.5-flash-8b-exp-0827";
+const GEMINI_URL: &str = "https://generativelanguage.googleapis.com/v1beta/models";
+const OPENAI_URL: &str = "http://127.0.0.1:8080/v1";
+const OPENAI_MODEL: &str = "local";
+const MAX_TOKENS: u32 = 1000;
+const API_TIMEOUT: Duration = Duration::from_secs(30);
+const MEMORY_TURNS: usize = 10;
+const MEMORY_MINUTES: u64 = 60;
+const POST_LENGTH: usize = 2000;
+const MAX_POSTS: usize = 5;
+
+#[derive(Debug)]
+pub enum AssistantErr {
+    MissingApiKey,
+    UnknownBackend(String),
+    Http(reqwest::Error),
+    Api(u16, String),
+    UnexpectedResponse(String),
+}
+
+impl Display for AssistantErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssistantErr::MissingApiKey => write!(f, "missing api key"),
+            AssistantErr::UnknownBackend(b) => write!(f, "unknown backend {}", b),
+            AssistantErr::Http(e) => write!(f, "{}", e),
+            AssistantErr::Api(status, body) => write!(f, "api error {}: {}", status, body),
+            AssistantErr::UnexpectedResponse(body) => write!(f, "unexpected response: {}", body),
+        }
+    }
+}
+
+impl error::Error for AssistantErr {}
+
+impl From<reqwest::Error> for AssistantErr {
+    fn from(value: reqwest::Error) -> Self {
+        AssistantErr::Http(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    #[default]
+    Disabled,
+    Gemini,
+    /// Any OpenAI compatible endpoint (llama.cpp server, ollama, ...)
+    Openai,
+    /// Answers with the question, to try the bot without an api
+    Fake,
+}
+
+/// `[assistant]` section of the config file, every field can be set in the environment
+/// with `BHC_ASSISTANT_<FIELD>`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AssistantConfig {
+    pub backend: BackendKind,
+    pub url: Option<String>,
+    pub model: Option<String>,
+    pub api_key: Option<String>,
+    pub system_prompt: Option<String>,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<u32>,
+    /// Questions and answers remembered per user and channel, 0 to forget everything
+    pub memory_turns: Option<usize>,
+    /// A conversation is forgotten after this many idle minutes
+    pub memory_minutes: Option<u64>,
+    /// Last chat messages sent along with the question
+    pub context_messages: Option<usize>,
+    /// Questions waiting for an answer, more are refused
+    pub queue_size: Option<usize>,
+    /// Questions a user can ask per minute, 0 for no limit
+    pub user_per_minute: Option<usize>,
+    /// Questions everybody together can ask per minute, 0 for no limit
+    pub global_per_minute: Option<usize>,
+    /// Longest message the server accepts, longer answers are split in several posts
+    pub post_length: Option<usize>,
+    /// Posts per answer, the rest is dropped, 0 for no limit
+    pub max_posts: Option<usize>,
+}
+
+impl AssistantConfig {
+    /// Override the config with the `BHC_ASSISTANT_*` environment variables.
+    pub fn with_env(mut self) -> Result<Self, AssistantErr> {
+        let var = |name: &str| env::var(format!("BHC_ASSISTANT_{}", name)).ok().filter(|v| !v.is_empty());
+        if let Some(backend) = var("BACKEND") {
+            self.backend = match backend.to_lowercase().as_str() {
+                "disabled" => BackendKind::Disabled,
+                "gemini" => BackendKind::Gemini,
+                "openai" => BackendKind::Openai,
+                "fake" => BackendKind::Fake,
+                _ => return Err(AssistantErr::UnknownBackend(backend)),
+            };
+        }
+        self.url = var("URL").or(self.url);
+        self.model = var("MODEL").or(self.model);
+        self.api_key = var("API_KEY").or(self.api_key);
+        self.system_prompt = var("SYSTEM_PROMPT").or(self.system_prompt);
+        Ok(self)
+    }
+
+    pub fn chunker(&self) -> Chunker {
+        Chunker {
+            max_length: self.post_length.unwrap_or(POST_LENGTH),
+            max_chunks: self.max_posts.unwrap_or(MAX_POSTS),
+        }
+    }
+}
+
+/// A chat completion api, `turns` ends with the question.
+pub trait Backend: Send {
+    fn complete(&self, system: &str, turns: &[Turn]) -> Result<String, AssistantErr>;
+}
+
+pub struct Gemini {
+    client: Client,
+    url: String,
+    api_key: String,
+    temperature: f64,
+    max_tokens: u32,
+}
+
+impl Gemini {
+    fn request_body(&self, system: &str, turns: &[Turn]) -> Value {
+        let contents: Vec<Value> = turns
+            .iter()
+            .map(|t| {
+                let role = match t.role {
+                    Role::User => "user",
+                    Role::Assistant => "model",
+                };
+                json!({"role": role, "parts": [{"text": t.text}]})
+            })
+            .collect();
+        json!({
+            "contents": contents,
+            "systemInstruction": {
+                "role": "model",
+                "parts": [{"text": system}]
+            },
+            "generationConfig": {
+                "temperature": self.temperature,
+                "topP": 0.95,
+                "topK": 64,
+                "maxOutputTokens": self.max_tokens,
+                "responseMimeType": "text/plain"
+            }
+        })
+    }
+
+    fn parse_response(body: &Value) -> Option<String> {
+        body["candidates"][0]["content"]["parts"][0]["text"].as_str().map(|s| s.to_owned())
+    }
+}
+
+impl Backend for Gemini {
+    fn complete(&self, system: &str, turns: &[Turn]) -> Result<String, AssistantErr> {
+        let req = self
+            .client
+            .post(&self.url)
+            .query(&[("key", &self.api_key)])
+            .json(&self.request_body(system, turns));
+        let body = send(req)?;
+        Self::parse_response(&body).ok_or_else(|| AssistantErr::UnexpectedResponse(body.to_string()))
+    }
+}
+
+pub struct OpenAi {
+    client: Client,
+    url: String,
+    model: String,
+    api_key: Option<String>,
+    temperature: f64,
+    max_tokens: u32,
+}
+
+impl OpenAi {
+    fn request_body(&self, system: &str, turns: &[Turn]) -> Value {
+        let mut messages = vec![json!({"role": "system", "content": system})];
+        messages.extend(turns.iter().map(|t| {
+            let role = match t.role {
+                Role::User => "user",
+                Role::Assistant => "assistant",
+            };
+            json!({"role": role, "content": t.text})
+        }));
+        json!({
+            "model": self.model,
+            "messages": messages,
+            "temperature": self.temperature,
+            "max_tokens": self.max_tokens,
+        })
+    }
+
+    fn parse_response(body: &Value) -> Option<String> {
+        body["choices"][0]["message"]["content"].as_str().map(|s| s.to_owned())
+    }
+}
+
+impl Backend for OpenAi {
+    fn complete(&self, system: &str, turns: &[Turn]) -> Result<String, AssistantErr> {
+        let mut req = self
+            .client
+            .post(format!("{}/chat/completions", self.url.trim_end_matches('/')))
+            .json(&self.request_body(system, turns));
+        if let Some(api_key) = &self.api_key {
+            req = req.bearer_auth(api_key);
+        }
+        let body = send(req)?;
+        Self::parse_response(&body).ok_or_else(|| AssistantErr::UnexpectedResponse(body.to_string()))
+    }
+}
+
+fn send(req: reqwest::blocking::RequestBuilder) -> Result<Value, AssistantErr> {
+    let resp = req.send()?;
+    let status = resp.status();
+    let text = resp.text()?;
+    if !status.is_success() {
+        return Err(AssistantErr::Api(status.as_u16(), text));
+    }
+    serde_json::from_str(&text).map_err(|_| AssistantErr::UnexpectedResponse(text))
+}
+
+/// Backend answering without any api.
+pub struct Fake;
+
+impl Backend for Fake {
+    fn complete(&self, _system: &str, turns: &[Turn]) -> Result<String, AssistantErr> {
+        let question = turns.last().map(|t| t.text.as_str()).unwrap_or_default();
+        Ok(format!("You asked: {}", question))
+    }
+}
+
+/// The `askdan?` assistant.
+pub struct Assistant {
+    backend: Box<dyn Backend>,
+    system_prompt: String,
+    memory: Memory,
+    context_messages: usize,
+}
+
+impl Assistant {
+    pub fn new(backend: Box<dyn Backend>, system_prompt: &str) -> Self {
+        Self {
+            backend,
+            system_prompt: system_prompt.to_owned(),
+            memory: Memory::new(MEMORY_TURNS, Duration::from_secs(MEMORY_MINUTES * 60)),
+            context_messages: 0,
+        }
+    }
+
+    /// Build the configured backend, `None` if the assistant is disabled.
+    pub fn from_config(cfg: &AssistantConfig) -> Result<Option<Self>, AssistantErr> {
+        let client = || Client::builder().timeout(API_TIMEOUT).build();
+        let backend: Box<dyn Backend> = match cfg.backend {
+            BackendKind::Disabled => return Ok(None),
+            BackendKind::Fake => Box::new(Fake),
+            BackendKind::Gemini => {
+                let model = cfg.model.as_deref().unwrap_or(GEMINI_MODEL);
+                Box::new(Gemini {
+                    client: client()?,
+                    url: cfg
+                        .url
+                        .clone()
+                        .unwrap_or_else(|| format!("{}/{}:generateContent", GEMINI_URL, model)),
+                    api_key: cfg.api_key.clone().ok_or(AssistantErr::MissingApiKey)?,
+                    temperature: cfg.temperature.unwrap_or(2.0),
+                    max_tokens: cfg.max_tokens.unwrap_or(MAX_TOKENS),
+                })
+            }
+            BackendKind::Openai => Box::new(OpenAi {
+                client: client()?,
+                url: cfg.url.clone().unwrap_or_else(|| OPENAI_URL.to_owned()),
+                model: cfg.model.clone().unwrap_or_else(|| OPENAI_MODEL.to_owned()),
+                api_key: cfg.api_key.clone(),
+                temperature: cfg.temperature.unwrap_or(0.7),
+                max_tokens: cfg.max_tokens.unwrap_or(MAX_TOKENS),
+            }),
+        };
+        let system_prompt = cfg.system_prompt.as_deref().unwrap_or(DEFAULT_SYSTEM_PROMPT);
+        let mut assistant = Self::new(backend, system_prompt);
+        assistant.memory = Memory::new(
+            cfg.memory_turns.unwrap_or(MEMORY_TURNS),
+            Duration::from_secs(cfg.memory_minutes.unwrap_or(MEMORY_MINUTES) * 60),
+        );
+        assistant.context_messages = cfg.context_messages.unwrap_or(0);
+        Ok(Some(assistant))
+    }
+
+    /// How many recent chat messages `ask` wants as context.
+    pub fn context_messages(&self) -> usize {
+        self.context_messages
+    }
+
+    /// Answer `question` of `user`, following up on their previous questions in `channel`.
+    /// `context` are recent chat messages, oldest first.
+    pub fn ask(
+        &mut self,
+        user: &str,
+        channel: &str,
+        question: &str,
+        users: &Users,
+        context: &[String],
+    ) -> Result<String, AssistantErr> {
+        let now = Instant::now();
+        let mut turns = self.memory.turns(user, channel, now);
+        turns.push(Turn::user(question));
+        let answer = self.backend.complete(&self.system_prompt(users, context), &turns)?;
+        self.memory.push(user, channel, question, &answer, now);
+        Ok(answer)
+    }
+
+    /// Forget the conversations of `user`, false if there was nothing to forget.
+    pub fn reset(&mut self, user: &str) -> bool {
+        self.memory.reset(user)
+    }
+
+    fn system_prompt(&self, users: &Users, context: &[String]) -> String {
+        let names = |list: &[(tui::style::Color, String)]| {
+            format!("{:?}", list.iter().map(|(_, name)| name).collect::<Vec<_>>())
+        };
+        let mut prompt = self
+            .system_prompt
+            .replace("{members}", &names(&users.members))
+            .replace("{staff}", &names(&users.staff))
+            .replace("{admins}", &names(&users.admin));
+        if !context.is_empty() {
+            prompt += "\n\nRecent chat messages:\n";
+            prompt += &context.join("\n");
+        }
+        prompt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    type Calls = Arc<Mutex<Vec<(String, Vec<Turn>)>>>;
+
+    // Records what the assistant sends
+    struct Recorder(Calls);
+
+    impl Backend for Recorder {
+        fn complete(&self, system: &str, turns: &[Turn]) -> Result<String, AssistantErr> {
+            let mut calls = self.0.lock().unwrap();
+            calls.push((system.to_owned(), turns.to_vec()));
+            Ok(format!("answer {}", calls.len()))
+        }
+    }
+
+    #[test]
+    fn ask_fills_the_prompt() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let mut assistant = Assistant::new(Box::new(Recorder(calls.clone())), "members={members} staff={staff}");
+        let users = Users {
+            members: vec![(tui::style::Color::White, "alice".to_owned())],
+            ..Default::default()
+        };
+        assert_eq!(assistant.ask("bob", "public", "why?", &users, &[]).unwrap(), "answer 1");
+        assert_eq!(
+            calls.lock().unwrap()[0],
+            ("members=[\"alice\"] staff=[]".to_owned(), vec![Turn::user("why?")])
+        );
+    }
+
+    #[test]
+    fn follow_up_questions() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let mut assistant = Assistant::new(Box::new(Recorder(calls.clone())), "be nice");
+        let users = Users::default();
+        let context = ["carol - hi all".to_owned()];
+        assistant.ask("bob", "public", "why?", &users, &[]).unwrap();
+        assistant.ask("alice", "public", "who?", &users, &[]).unwrap();
+        assistant.ask("Bob", "public", "really?", &users, &context).unwrap();
+        let (system, turns) = calls.lock().unwrap()[2].clone();
+        assert_eq!(system, "be nice\n\nRecent chat messages:\ncarol - hi all");
+        assert_eq!(turns, vec![Turn::user("why?"), Turn::assistant("answer 1"), Turn::user("really?")]);
+
+        assert!(assistant.reset("bob"));
+        assistant.ask("bob", "public", "again?", &users, &[]).unwrap();
+        assert_eq!(calls.lock().unwrap()[3].1, vec![Turn::user("again?")]);
+    }
+
+    #[test]
+    fn backends_from_config() {
+        let cfg = AssistantConfig::default();
+        assert!(Assistant::from_config(&cfg).unwrap().is_none());
+        let cfg = AssistantConfig {
+            backend: BackendKind::Gemini,
+            ..Default::default()
+        };
+        assert!(matches!(Assistant::from_config(&cfg), Err(AssistantErr::MissingApiKey)));
+        let cfg = AssistantConfig {
+            backend: BackendKind::Fake,
+            ..Default::default()
+        };
+        let mut assistant = Assistant::from_config(&cfg).unwrap().unwrap();
+        assert_eq!(assistant.ask("bob", "pm", "hi", &Users::default(), &[]).unwrap(), "You asked: hi");
+    }
+
+    #[test]
+    fn openai_compatible_endpoint() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1", server.server_addr().to_ip().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut req = server.recv().unwrap();
+            let mut body = String::new();
+            req.as_reader().read_to_string(&mut body).unwrap();
+            let auth = req
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv("Authorization"))
+                .map(|h| h.value.to_string());
+            let resp = json!({"choices": [{"message": {"role": "assistant", "content": "pong"}}]});
+            req.respond(tiny_http::Response::from_string(resp.to_string())).unwrap();
+            (request_messages(&body), auth)
+        });
+        let cfg = AssistantConfig {
+            backend: BackendKind::Openai,
+            url: Some(url),
+            api_key: Some("secret".to_owned()),
+            system_prompt: Some("be nice".to_owned()),
+            ..Default::default()
+        };
+        let mut assistant = Assistant::from_config(&cfg).unwrap().unwrap();
+        assert_eq!(assistant.ask("bob", "pm", "ping", &Users::default(), &[]).unwrap(), "pong");
+        let (messages, auth) = handle.join().unwrap();
+        assert_eq!(messages, vec!["system: be nice", "user: ping"]);
+        assert_eq!(auth.as_deref(), Some("Bearer secret"));
+    }
+
+    fn request_messages(body: &str) -> Vec<String> {
+        let body: Value = serde_json::from_str(body).unwrap();
+        body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| format!("{}: {}", m["role"].as_str().unwrap(), m["content"].as_str().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn response_formats() {
+        let gemini = json!({"candidates": [{"content": {"parts": [{"text": "hello"}]}}]});
+        assert_eq!(Gemini::parse_response(&gemini).as_deref(), Some("hello"));
+        let openai = json!({"choices": [{"message": {"role": "assistant", "content": "hi"}}]});
+        assert_eq!(OpenAi::parse_response(&openai).as_deref(), Some("hi"));
+        assert_eq!(OpenAi::parse_response(&gemini), None);
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::VecDeque;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bhcli::lechatphp::Users;
+use crossbeam_channel::{Sender, TrySendError};
+
+use super::{Assistant, AssistantConfig, AssistantErr, Chunker};
+
+const QUEUE_SIZE: usize = 5;
+const USER_PER_MINUTE: usize = 3;
+const GLOBAL_PER_MINUTE: usize = 20;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Called on the worker thread with the answer.
+pub type Reply = Box<dyn FnOnce(Result<String, AssistantErr>) + Send>;
+
+/// Everything the worker needs to answer, the chat may have changed once it gets to it.
+pub struct Question {
+    pub user: String,
+    pub channel: &'static str,
+    pub question: String,
+    pub users: Users,
+    pub context: Vec<String>,
+}
+
+enum Task {
+    Ask(Question, Reply),
+    Reset(String, Box<dyn FnOnce(bool) + Send>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejected {
+    UserLimit,
+    GlobalLimit,
+    Busy,
+}
+
+/// Questions per user and overall over the last minute, a limit of 0 is disabled.
+pub struct RateLimiter {
+    per_user: usize,
+    global: usize,
+    sent: VecDeque<(Instant, String)>,
+}
+
+impl RateLimiter {
+    pub fn new(per_user: usize, global: usize) -> Self {
+        Self {
+            per_user,
+            global,
+            sent: VecDeque::new(),
+        }
+    }
+
+    pub fn check(&mut self, user: &str, now: Instant) -> Result<(), Rejected> {
+        while self.sent.front().is_some_and(|(at, _)| now.duration_since(*at) >= RATE_WINDOW) {
+            self.sent.pop_front();
+        }
+        if self.global > 0 && self.sent.len() >= self.global {
+            return Err(Rejected::GlobalLimit);
+        }
+        let user = user.to_lowercase();
+        if self.per_user > 0 && self.sent.iter().filter(|(_, u)| *u == user).count() >= self.per_user {
+            return Err(Rejected::UserLimit);
+        }
+        Ok(())
+    }
+
+    pub fn record(&mut self, user: &str, now: Instant) {
+        self.sent.push_back((now, user.to_lowercase()));
+    }
+}
+
+/// Runs the assistant on its own thread so slow apis never block the chat.
+pub struct Worker {
+    tasks: Sender<Task>,
+    limiter: RateLimiter,
+    context_messages: usize,
+    chunker: Chunker,
+}
+
+impl Worker {
+    pub fn spawn(mut assistant: Assistant, cfg: &AssistantConfig) -> Self {
+        let (tasks, rx) = crossbeam_channel::bounded(cfg.queue_size.unwrap_or(QUEUE_SIZE));
+        let context_messages = assistant.context_messages();
+        thread::spawn(move || {
+            for task in rx {
+                match task {
+                    Task::Ask(q, reply) => {
+                        reply(assistant.ask(&q.user, q.channel, &q.question, &q.users, &q.context))
+                    }
+                    Task::Reset(user, reply) => reply(assistant.reset(&user)),
+                }
+            }
+        });
+        Self {
+            tasks,
+            limiter: RateLimiter::new(
+                cfg.user_per_minute.unwrap_or(USER_PER_MINUTE),
+                cfg.global_per_minute.unwrap_or(GLOBAL_PER_MINUTE),
+            ),
+            context_messages,
+            chunker: cfg.chunker(),
+        }
+    }
+
+    /// How answers are split in posts.
+    pub fn chunker(&self) -> Chunker {
+        self.chunker
+    }
+
+    /// How many recent chat messages a question should come with.
+    pub fn context_messages(&self) -> usize {
+        self.context_messages
+    }
+
+    /// Queue a question, `reply` is not called when it is rejected.
+    pub fn ask(&mut self, question: Question, reply: Reply) -> Result<(), Rejected> {
+        let now = Instant::now();
+        self.limiter.check(&question.user, now)?;
+        let user = question.user.clone();
+        self.send(Task::Ask(question, reply))?;
+        self.limiter.record(&user, now);
+        Ok(())
+    }
+
+    /// Queue forgetting the conversations of `user`.
+    pub fn reset(&self, user: &str, reply: impl FnOnce(bool) + Send + 'static) -> Result<(), Rejected> {
+        self.send(Task::Reset(user.to_owned(), Box::new(reply)))
+    }
+
+    fn send(&self, task: Task) -> Result<(), Rejected> {
+        self.tasks.try_send(task).map_err(|err| match err {
+            TrySendError::Full(_) => Rejected::Busy,
+            TrySendError::Disconnected(_) => {
+                log::error!("assistant worker stopped");
+                Rejected::Busy
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assistant::{Backend, Turn};
+    use crossbeam_channel::Receiver;
+
+    // Answers once the test lets it
+    struct Gate(Receiver<()>);
+
+    impl Backend for Gate {
+        fn complete(&self, _system: &str, turns: &[Turn]) -> Result<String, AssistantErr> {
+            self.0.recv().unwrap();
+            Ok(turns.last().unwrap().text.clone())
+        }
+    }
+
+    fn question(user: &str, text: &str) -> Question {
+        Question {
+            user: user.to_owned(),
+            channel: "public",
+            question: text.to_owned(),
+            users: Users::default(),
+            context: vec![],
+        }
+    }
+
+    #[test]
+    fn rate_limits() {
+        let mut limiter = RateLimiter::new(2, 3);
+        let now = Instant::now();
+        for user in ["bob", "Bob", "alice"] {
+            limiter.check(user, now).unwrap();
+            limiter.record(user, now);
+        }
+        assert_eq!(limiter.check("carol", now), Err(Rejected::GlobalLimit));
+        let later = now + Duration::from_secs(30);
+        assert_eq!(limiter.check("carol", later), Err(Rejected::GlobalLimit));
+        let later = now + RATE_WINDOW;
+        limiter.check("bob", later).unwrap();
+        limiter.record("bob", later);
+        limiter.record("bob", later);
+        assert_eq!(limiter.check("BOB", later), Err(Rejected::UserLimit));
+    }
+
+    #[test]
+    fn full_queue_is_busy() {
+        let (open, gate) = crossbeam_channel::unbounded();
+        let assistant = Assistant::new(Box::new(Gate(gate)), "");
+        let cfg = AssistantConfig {
+            queue_size: Some(1),
+            user_per_minute: Some(0),
+            global_per_minute: Some(0),
+            ..Default::default()
+        };
+        let mut worker = Worker::spawn(assistant, &cfg);
+        let (answers_tx, answers) = crossbeam_channel::unbounded();
+        let reply = || -> Reply {
+            let answers_tx = answers_tx.clone();
+            Box::new(move |answer| answers_tx.send(answer.unwrap()).unwrap())
+        };
+
+        // The first question keeps the worker busy, the second one waits in the queue
+        worker.ask(question("bob", "one"), reply()).unwrap();
+        let mut queued = 1;
+        while queued < 2 {
+            match worker.ask(question("bob", "two"), reply()) {
+                Ok(()) => queued += 1,
+                Err(Rejected::Busy) => thread::sleep(Duration::from_millis(10)),
+                Err(err) => panic!("{:?}", err),
+            }
+        }
+        assert_eq!(worker.ask(question("bob", "three"), reply()).err(), Some(Rejected::Busy));
+
+        open.send(()).unwrap();
+        open.send(()).unwrap();
+        assert_eq!(answers.recv().unwrap(), "one");
+        assert_eq!(answers.recv().unwrap(), "two");
+    }
+}
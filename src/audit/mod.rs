@@ -0,0 +1,323 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{Local, TimeZone, Utc};
+use rusqlite::{params, Connection, Row};
+use serde_json::{json, Value};
+
+/// Name used as actor for what the Dantca bot did on its own.
+pub const BOT_ACTOR: &str = "bot";
+
+/// How many entries the TUI shows.
+pub const VIEW_LIMIT: usize = 1000;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS actions (
+    id     INTEGER PRIMARY KEY AUTOINCREMENT,
+    at     INTEGER NOT NULL,
+    kind   TEXT NOT NULL,
+    target TEXT NOT NULL COLLATE NOCASE,
+    rule   TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    text   TEXT NOT NULL,
+    actor  TEXT NOT NULL,
+    weight INTEGER NOT NULL DEFAULT 1,
+    until  INTEGER
+);
+CREATE INDEX IF NOT EXISTS actions_target ON actions(target);
+";
+
+const COLUMNS: &str = "at, kind, target, rule, reason, text, actor, weight, until";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditKind {
+    Warn,
+    Kick,
+    SilentKick,
+    ImposterBan,
+}
+
+impl AuditKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditKind::Warn => "warn",
+            AuditKind::Kick => "kick",
+            AuditKind::SilentKick => "silent_kick",
+            AuditKind::ImposterBan => "imposter_ban",
+        }
+    }
+
+    fn from_str(s: &str) -> Self {
+        match s {
+            "warn" => AuditKind::Warn,
+            "silent_kick" => AuditKind::SilentKick,
+            "imposter_ban" => AuditKind::ImposterBan,
+            _ => AuditKind::Kick,
+        }
+    }
+}
+
+/// One moderation action.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    /// Unix timestamp
+    pub at: i64,
+    pub kind: AuditKind,
+    pub target: String,
+    /// Rule that triggered the action, `manual` for a kick typed by a human
+    pub rule: String,
+    pub reason: String,
+    /// Message that triggered the action, the nickname for imposter bans
+    pub text: String,
+    /// `bot` or the nickname of the human who did it, `api:<connection>` or `irc:<nickname>`
+    /// for kicks sent through the api or the irc gateway
+    pub actor: String,
+    /// Warning points of a warn
+    pub weight: u32,
+    /// Unix timestamp until which a kicked user is kicked again on sight
+    pub until: Option<i64>,
+}
+
+impl AuditEntry {
+    pub fn new(kind: AuditKind, target: &str, rule: &str, reason: &str, text: &str, actor: &str) -> Self {
+        Self {
+            at: Utc::now().timestamp(),
+            kind,
+            target: target.to_owned(),
+            rule: rule.to_owned(),
+            reason: reason.to_owned(),
+            text: text.to_owned(),
+            actor: actor.to_owned(),
+            weight: 1,
+            until: None,
+        }
+    }
+
+    /// Local date time, for display.
+    pub fn date(&self) -> String {
+        Local
+            .timestamp_opt(self.at, 0)
+            .single()
+            .map(|d| d.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "at": self.at,
+            "date": self.date(),
+            "kind": self.kind.as_str(),
+            "target": self.target,
+            "rule": self.rule,
+            "reason": self.reason,
+            "text": self.text,
+            "actor": self.actor,
+            "weight": self.weight,
+            "until": self.until,
+        })
+    }
+}
+
+/// Moderation audit log, one sqlite database per server/profile.
+pub struct AuditLog {
+    conn: Connection,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        Self::init(Connection::open(path)?)
+    }
+
+    /// Used when there is no database file, nothing survives a restart.
+    pub fn open_in_memory() -> rusqlite::Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> rusqlite::Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    pub fn record(&self, entry: &AuditEntry) -> rusqlite::Result<()> {
+        self.conn.execute(
+            &format!("INSERT INTO actions ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)", COLUMNS),
+            params![
+                entry.at,
+                entry.kind.as_str(),
+                entry.target,
+                entry.rule,
+                entry.reason,
+                entry.text,
+                entry.actor,
+                entry.weight,
+                entry.until
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The `limit` most recent entries, newest first.
+    pub fn recent(&self, limit: usize) -> rusqlite::Result<Vec<AuditEntry>> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!("SELECT {} FROM actions ORDER BY id DESC LIMIT ?1", COLUMNS))?;
+        let rows = stmt.query_map([limit as i64], row_to_entry)?;
+        rows.collect()
+    }
+
+    /// The `limit` most recent kicks of any kind, newest first.
+    pub fn kicks(&self, limit: usize) -> rusqlite::Result<Vec<AuditEntry>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM actions WHERE kind != 'warn' ORDER BY id DESC LIMIT ?1",
+            COLUMNS
+        ))?;
+        let rows = stmt.query_map([limit as i64], row_to_entry)?;
+        rows.collect()
+    }
+
+    /// Warning points of `target`: the weights of their last warnings, stopping at the first
+    /// gap of more than `quiet_secs` (counting from `now`), so old warnings are forgotten.
+    pub fn warning_points(&self, target: &str, quiet_secs: i64, now: i64) -> rusqlite::Result<u32> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT at, weight FROM actions WHERE kind = 'warn' AND target = ?1 ORDER BY id DESC")?;
+        let mut rows = stmt.query([target])?;
+        let (mut points, mut last) = (0, now);
+        while let Some(row) = rows.next()? {
+            let (at, weight): (i64, u32) = (row.get(0)?, row.get(1)?);
+            if last - at > quiet_secs {
+                break;
+            }
+            points += weight;
+            last = at;
+        }
+        Ok(points)
+    }
+
+    /// Kick of `target` that is still running at `now`.
+    pub fn active_ban(&self, target: &str, now: i64) -> rusqlite::Result<Option<AuditEntry>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT {} FROM actions WHERE target = ?1 AND until > ?2 ORDER BY until DESC LIMIT 1",
+            COLUMNS
+        ))?;
+        let mut rows = stmt.query_map(params![target, now], row_to_entry)?;
+        rows.next().transpose()
+    }
+
+    /// Write every entry to `path`, JSON if the extension is `.json`, CSV otherwise.
+    /// Returns the number of exported entries.
+    pub fn export(&self, path: &Path) -> anyhow::Result<usize> {
+        let mut entries = self.recent(usize::MAX >> 1)?;
+        entries.reverse();
+        let content = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => export_json(&entries),
+            _ => export_csv(&entries),
+        };
+        fs::write(path, content)?;
+        Ok(entries.len())
+    }
+}
+
+fn row_to_entry(row: &Row) -> rusqlite::Result<AuditEntry> {
+    let kind: String = row.get(1)?;
+    Ok(AuditEntry {
+        at: row.get(0)?,
+        kind: AuditKind::from_str(&kind),
+        target: row.get(2)?,
+        rule: row.get(3)?,
+        reason: row.get(4)?,
+        text: row.get(5)?,
+        actor: row.get(6)?,
+        weight: row.get(7)?,
+        until: row.get(8)?,
+    })
+}
+
+pub fn export_json(entries: &[AuditEntry]) -> String {
+    let entries: Vec<Value> = entries.iter().map(|e| e.to_json()).collect();
+    serde_json::to_string_pretty(&entries).unwrap()
+}
+
+pub fn export_csv(entries: &[AuditEntry]) -> String {
+    let mut out = String::from("date,kind,target,rule,reason,text,actor,weight,until\n");
+    for e in entries {
+        let fields = [
+            e.date(),
+            e.kind.as_str().to_owned(),
+            e.target.clone(),
+            e.rule.clone(),
+            e.reason.clone(),
+            e.text.clone(),
+            e.actor.clone(),
+            e.weight.to_string(),
+            e.until.map(|u| u.to_string()).unwrap_or_default(),
+        ];
+        let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        out += &fields.join(",");
+        out.push('\n');
+    }
+    out
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// Database file for a given server url and profile, next to the config file.
+pub fn default_path(url: &str, profile: &str) -> Option<PathBuf> {
+    crate::util::profile_db_path("audit", url, profile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warnings_decay() {
+        let audit = AuditLog::open_in_memory().unwrap();
+        let warn = |target: &str, at: i64, weight: u32| AuditEntry {
+            at,
+            weight,
+            ..AuditEntry::new(AuditKind::Warn, target, "betting", "no", "betting?", BOT_ACTOR)
+        };
+        audit.record(&warn("alice", 1000, 1)).unwrap();
+        audit.record(&warn("alice", 5000, 1)).unwrap();
+        audit.record(&warn("alice", 5500, 2)).unwrap();
+        audit.record(&warn("bob", 5500, 1)).unwrap();
+        // The first warning is more than 30 minutes older than the next one
+        assert_eq!(audit.warning_points("ALICE", 1800, 6000).unwrap(), 3);
+        // Quiet for long enough, everything is forgotten
+        assert_eq!(audit.warning_points("alice", 1800, 9000).unwrap(), 0);
+        assert_eq!(audit.warning_points("carol", 1800, 6000).unwrap(), 0);
+
+        let kick = AuditEntry {
+            until: Some(6000),
+            ..AuditEntry::new(AuditKind::Kick, "alice", "manual", "bye", "", "staff1")
+        };
+        audit.record(&kick).unwrap();
+        assert_eq!(audit.active_ban("alice", 5900).unwrap(), Some(kick.clone()));
+        assert_eq!(audit.active_ban("alice", 6000).unwrap(), None);
+        assert_eq!(audit.active_ban("bob", 5900).unwrap(), None);
+
+        assert_eq!(audit.kicks(10).unwrap(), vec![kick]);
+        assert_eq!(audit.recent(10).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn export_formats() {
+        let mut entry = AuditEntry::new(AuditKind::SilentKick, "bob", "cp", "CP is a", "say \"hi\", all", BOT_ACTOR);
+        entry.at = 0;
+        let csv = export_csv(&[entry.clone()]);
+        let line = csv.lines().nth(1).unwrap();
+        assert!(line.ends_with(",silent_kick,bob,cp,CP is a,\"say \"\"hi\"\", all\",bot,1,"), "{}", line);
+
+        let json: Value = serde_json::from_str(&export_json(&[entry])).unwrap();
+        assert_eq!(json[0]["kind"], "silent_kick");
+        assert_eq!(json[0]["text"], "say \"hi\", all");
+        assert_eq!(json[0]["at"], 0);
+    }
+}
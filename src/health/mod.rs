@@ -0,0 +1,130 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use rand::Rng;
+
+/// How the connection to the chat is doing, shown in the status line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConnState {
+    /// Logged in and the last request went through
+    Connected,
+    /// Logged in but requests fail
+    Degraded,
+    /// Logging in again
+    Reconnecting,
+    /// The server cannot be reached, or we gave up logging in
+    #[default]
+    Offline,
+}
+
+impl Display for ConnState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            ConnState::Connected => "connected",
+            ConnState::Degraded => "degraded",
+            ConnState::Reconnecting => "reconnecting",
+            ConnState::Offline => "offline",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// State of the connection of one client and the last error it got,
+/// updated by the login loop and the refresh and post threads.
+#[derive(Debug, Clone, Default)]
+pub struct Health {
+    state: ConnState,
+    last_error: Option<String>,
+}
+
+impl Health {
+    pub fn state(&self) -> ConnState {
+        self.state
+    }
+
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    /// A request went through.
+    pub fn succeeded(&mut self) {
+        if self.state == ConnState::Degraded {
+            self.state = ConnState::Connected;
+        }
+    }
+
+    /// A request failed while logged in.
+    pub fn failed(&mut self, err: &str) {
+        if self.state == ConnState::Connected {
+            self.state = ConnState::Degraded;
+        }
+        self.last_error = Some(err.to_owned());
+    }
+
+    /// The session is gone or the login failed, another login follows.
+    pub fn reconnecting(&mut self, err: Option<&str>) {
+        self.state = ConnState::Reconnecting;
+        if let Some(err) = err {
+            self.last_error = Some(err.to_owned());
+        }
+    }
+
+    /// Logged in again.
+    pub fn logged_in(&mut self) {
+        self.state = ConnState::Connected;
+    }
+
+    pub fn offline(&mut self, err: &str) {
+        self.state = ConnState::Offline;
+        self.last_error = Some(err.to_owned());
+    }
+}
+
+/// Delay before the attempt after `attempt` (1 for the first retry): `base` doubled every attempt up
+/// to `max`, of which a random half is taken off so that clients do not all retry at the same time.
+pub fn backoff(attempt: u32, base: Duration, max: Duration) -> Duration {
+    jittered_backoff(attempt, base, max, rand::thread_rng().gen())
+}
+
+// `jitter` is in [0, 1)
+fn jittered_backoff(attempt: u32, base: Duration, max: Duration, jitter: f64) -> Duration {
+    let delay = base.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1))).min(max);
+    delay / 2 + (delay / 2).mul_f64(jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let (base, max) = (Duration::from_secs(2), Duration::from_secs(60));
+        let delays: Vec<u64> = (1..=7).map(|a| jittered_backoff(a, base, max, 0.999).as_secs()).collect();
+        assert_eq!(delays, [1, 3, 7, 15, 31, 59, 59]);
+        assert_eq!(jittered_backoff(3, base, max, 0.0), Duration::from_secs(4));
+        assert_eq!(jittered_backoff(100, base, max, 0.0), Duration::from_secs(30));
+        let delay = backoff(2, base, max);
+        assert!(delay >= Duration::from_secs(2) && delay < Duration::from_secs(4), "{:?}", delay);
+    }
+
+    #[test]
+    fn transitions() {
+        let mut health = Health::default();
+        assert_eq!(health.state(), ConnState::Offline);
+        health.logged_in();
+        health.failed("timeout");
+        assert_eq!((health.state(), health.last_error()), (ConnState::Degraded, Some("timeout")));
+        health.succeeded();
+        assert_eq!(health.state(), ConnState::Connected);
+
+        // A late answer of the old session does not hide the relogin
+        health.reconnecting(Some("session expired"));
+        health.succeeded();
+        health.failed("timeout");
+        assert_eq!(health.state(), ConnState::Reconnecting);
+        health.offline("connection refused");
+        assert_eq!((health.state(), health.last_error()), (ConnState::Offline, Some("connection refused")));
+        health.logged_in();
+        assert_eq!((health.state(), health.last_error()), (ConnState::Connected, Some("connection refused")));
+    }
+}
@@ -0,0 +1,415 @@
+pub mod search;
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use bhcli::lechatphp::{get_message, Message, MessageType, StyledText};
+use chrono::{Datelike, Duration, NaiveDateTime, NaiveTime, Utc};
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{params, params_from_iter, Connection, Row};
+use serde_json::{json, Value};
+use tui::style::Color as tuiColor;
+
+/// How many messages are loaded back in memory on startup.
+pub const RELOAD_LIMIT: usize = 5000;
+/// Maximum number of results returned by a search.
+pub const SEARCH_LIMIT: usize = 500;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS messages (
+    id          INTEGER PRIMARY KEY AUTOINCREMENT,
+    msg_id      INTEGER,
+    typ         TEXT NOT NULL,
+    date        TEXT NOT NULL,
+    -- Unix timestamp of the date, in the time zone of the server,
+    -- 0 when the date does not fit the datetime format and only `date` tells messages apart
+    sent_at     INTEGER NOT NULL,
+    -- Empty for system messages
+    author      TEXT NOT NULL,
+    recipient   TEXT,
+    body        TEXT NOT NULL,
+    text        TEXT NOT NULL,
+    styled      TEXT NOT NULL,
+    upload_link TEXT,
+    deleted     INTEGER NOT NULL DEFAULT 0,
+    UNIQUE(sent_at, date, author, text)
+);
+CREATE INDEX IF NOT EXISTS messages_author ON messages(author);
+
+-- Full-text index over the message body, kept in sync with triggers
+CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(body, content='messages', content_rowid='id');
+CREATE TRIGGER IF NOT EXISTS messages_fts_ai AFTER INSERT ON messages BEGIN
+    INSERT INTO messages_fts(rowid, body) VALUES (new.id, new.body);
+END;
+CREATE TRIGGER IF NOT EXISTS messages_fts_ad AFTER DELETE ON messages BEGIN
+    INSERT INTO messages_fts(messages_fts, rowid, body) VALUES ('delete', old.id, old.body);
+END;
+";
+
+const MESSAGE_COLUMNS: &str = "m.msg_id, m.typ, m.date, m.upload_link, m.styled, m.deleted";
+
+/// Local message history, one sqlite database per server/profile.
+pub struct History {
+    conn: Mutex<Connection>,
+    members_tag: String,
+    datetime_fmt: String,
+}
+
+impl History {
+    pub fn open(path: &Path, members_tag: &str, datetime_fmt: &str) -> rusqlite::Result<Self> {
+        Self::init(Connection::open(path)?, members_tag, datetime_fmt)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory(members_tag: &str, datetime_fmt: &str) -> rusqlite::Result<Self> {
+        Self::init(Connection::open_in_memory()?, members_tag, datetime_fmt)
+    }
+
+    fn init(conn: Connection, members_tag: &str, datetime_fmt: &str) -> rusqlite::Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+            members_tag: members_tag.to_owned(),
+            datetime_fmt: datetime_fmt.to_owned(),
+        })
+    }
+
+    // What identifies a message with its date: when it was sent, who sent it and its text
+    fn key(&self, msg: &Message, now: NaiveDateTime) -> (i64, String, String) {
+        let text = msg.text.text();
+        let author = get_message(&msg.text, &self.members_tag).map(|(from, _, _)| from).unwrap_or_default();
+        let sent_at = sent_at(&msg.date, &self.datetime_fmt, now).map_or(0, |at| at.and_utc().timestamp());
+        (sent_at, author, text)
+    }
+
+    /// Store the messages we don't know about yet, already stored ones are ignored.
+    pub fn record(&self, messages: &[Message]) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT OR IGNORE INTO messages
+                 (msg_id, typ, date, sent_at, author, recipient, body, text, styled, upload_link, deleted)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            )?;
+            let now = Utc::now().naive_utc();
+            // The view lists the newest messages first, store them oldest first
+            for msg in messages.iter().rev() {
+                let (sent_at, author, text) = self.key(msg, now);
+                let (recipient, body) = match get_message(&msg.text, &self.members_tag) {
+                    Some((_, to, body)) => (to, body),
+                    None => (None, text.clone()),
+                };
+                stmt.execute(params![
+                    msg.id.map(|id| id as i64),
+                    typ_to_str(&msg.typ),
+                    msg.date,
+                    sent_at,
+                    author,
+                    recipient,
+                    body,
+                    text,
+                    styled_to_json(&msg.text).to_string(),
+                    msg.upload_link,
+                    msg.deleted,
+                ])?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// Flag messages that disappeared from the server.
+    pub fn mark_deleted(&self, messages: &[Message]) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "UPDATE messages SET deleted = 1 WHERE sent_at = ?1 AND date = ?2 AND author = ?3 AND text = ?4",
+            )?;
+            let now = Utc::now().naive_utc();
+            for msg in messages {
+                let (sent_at, author, text) = self.key(msg, now);
+                stmt.execute(params![sent_at, msg.date, author, text])?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// Load the `limit` most recent messages, newest first like the chat view.
+    pub fn load(&self, limit: usize) -> rusqlite::Result<Vec<Message>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM messages m ORDER BY m.id DESC LIMIT ?1",
+            MESSAGE_COLUMNS
+        ))?;
+        let rows = stmt.query_map([limit as i64], row_to_message)?;
+        rows.collect()
+    }
+
+    /// Search the whole history, newest matches first.
+    pub fn search(&self, query: &search::SearchQuery, limit: usize) -> rusqlite::Result<Vec<Message>> {
+        let conn = self.conn.lock().unwrap();
+        let mut sql = format!("SELECT {} FROM messages m", MESSAGE_COLUMNS);
+        let mut conds: Vec<&str> = vec![];
+        let mut args: Vec<SqlValue> = vec![];
+        if let Some(fts) = query.fts_query() {
+            sql += " JOIN messages_fts ON messages_fts.rowid = m.id";
+            conds.push("messages_fts MATCH ?");
+            args.push(SqlValue::Text(fts));
+        }
+        if let Some(from) = &query.from {
+            conds.push("m.author = ? COLLATE NOCASE");
+            args.push(SqlValue::Text(from.to_owned()));
+        }
+        if let Some(to) = &query.to {
+            conds.push("m.recipient = ? COLLATE NOCASE");
+            args.push(SqlValue::Text(to.to_owned()));
+        }
+        if let Some(since) = query.since {
+            conds.push("m.sent_at >= ?");
+            args.push(SqlValue::Integer(since.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp()));
+        }
+        if let Some(until) = query.until {
+            let end = until.and_hms_opt(0, 0, 0).unwrap() + Duration::days(1);
+            conds.push("m.sent_at < ?");
+            args.push(SqlValue::Integer(end.and_utc().timestamp()));
+        }
+        if let Some(re) = query.regex.clone() {
+            conn.create_scalar_function(
+                "bhcli_regexp",
+                1,
+                FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+                move |ctx| Ok(re.is_match(&ctx.get::<String>(0)?)),
+            )?;
+            conds.push("bhcli_regexp(m.body)");
+        }
+        if !conds.is_empty() {
+            sql += " WHERE ";
+            sql += &conds.join(" AND ");
+        }
+        sql += " ORDER BY m.id DESC LIMIT ?";
+        args.push(SqlValue::Integer(limit as i64));
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt.query_map(params_from_iter(args), row_to_message)?;
+        rows.collect()
+    }
+}
+
+fn row_to_message(row: &Row) -> rusqlite::Result<Message> {
+    let id: Option<i64> = row.get(0)?;
+    let typ: String = row.get(1)?;
+    let styled: String = row.get(4)?;
+    let text = serde_json::from_str(&styled)
+        .map(|v| styled_from_json(&v))
+        .unwrap_or(StyledText::None);
+    let mut msg = Message::new(
+        id.map(|id| id as usize),
+        typ_from_str(&typ),
+        row.get(2)?,
+        row.get(3)?,
+        text,
+    );
+    msg.deleted = row.get(5)?;
+    Ok(msg)
+}
+
+/// Full date of a message from the date shown by the chat, which has no year and sometimes
+/// only the time: the last one that is not after `now`, with some slack for the time zone
+/// of the server.
+fn sent_at(date: &str, datetime_fmt: &str, now: NaiveDateTime) -> Option<NaiveDateTime> {
+    let slack = Duration::hours(14);
+    let this_year = format!("{}-{}", now.year(), date);
+    if let Ok(sent) = NaiveDateTime::parse_from_str(&this_year, &format!("%Y-{}", datetime_fmt)) {
+        return if sent > now + slack { sent.with_year(now.year() - 1) } else { Some(sent) };
+    }
+    let sent = now.date().and_time(NaiveTime::parse_from_str(date, datetime_fmt).ok()?);
+    Some(if sent > now + slack { sent - Duration::days(1) } else { sent })
+}
+
+/// Database file for a given server url and profile, next to the config file.
+pub fn default_path(url: &str, profile: &str) -> Option<PathBuf> {
+    crate::util::profile_db_path("history", url, profile)
+}
+
+fn typ_to_str(typ: &MessageType) -> &'static str {
+    match typ {
+        MessageType::UserMsg => "user",
+        MessageType::SysMsg => "sys",
+    }
+}
+
+fn typ_from_str(s: &str) -> MessageType {
+    match s {
+        "sys" => MessageType::SysMsg,
+        _ => MessageType::UserMsg,
+    }
+}
+
+fn color_to_str(color: &tuiColor) -> String {
+    match color {
+        tuiColor::Rgb(r, g, b) => format!("#{:02x}{:02x}{:02x}", r, g, b),
+        tuiColor::Indexed(i) => format!("i{}", i),
+        c => format!("{:?}", c),
+    }
+}
+
+fn color_from_str(s: &str) -> tuiColor {
+    if let Some(hex) = s.strip_prefix('#') {
+        if let Ok(v) = u32::from_str_radix(hex, 16) {
+            return tuiColor::Rgb((v >> 16) as u8, (v >> 8) as u8, v as u8);
+        }
+    }
+    if let Some(i) = s.strip_prefix('i').and_then(|i| i.parse().ok()) {
+        return tuiColor::Indexed(i);
+    }
+    match s {
+        "Reset" => tuiColor::Reset,
+        "Black" => tuiColor::Black,
+        "Red" => tuiColor::Red,
+        "Green" => tuiColor::Green,
+        "Yellow" => tuiColor::Yellow,
+        "Blue" => tuiColor::Blue,
+        "Magenta" => tuiColor::Magenta,
+        "Cyan" => tuiColor::Cyan,
+        "Gray" => tuiColor::Gray,
+        "DarkGray" => tuiColor::DarkGray,
+        "LightRed" => tuiColor::LightRed,
+        "LightGreen" => tuiColor::LightGreen,
+        "LightYellow" => tuiColor::LightYellow,
+        "LightBlue" => tuiColor::LightBlue,
+        "LightMagenta" => tuiColor::LightMagenta,
+        "LightCyan" => tuiColor::LightCyan,
+        _ => tuiColor::White,
+    }
+}
+
+// StyledText is stored as json so it can be rendered exactly like a live message
+fn styled_to_json(st: &StyledText) -> Value {
+    match st {
+        StyledText::Styled(color, children) => json!({
+            "c": color_to_str(color),
+            "t": children.iter().map(styled_to_json).collect::<Vec<_>>(),
+        }),
+        StyledText::Text(t) => Value::String(t.to_owned()),
+        StyledText::None => Value::Null,
+    }
+}
+
+fn styled_from_json(v: &Value) -> StyledText {
+    match v {
+        Value::String(t) => StyledText::Text(t.to_owned()),
+        Value::Object(o) => {
+            let color = o
+                .get("c")
+                .and_then(|c| c.as_str())
+                .map(color_from_str)
+                .unwrap_or(tuiColor::White);
+            let children = o
+                .get("t")
+                .and_then(|t| t.as_array())
+                .map(|t| t.iter().map(styled_from_json).collect())
+                .unwrap_or_default();
+            StyledText::Styled(color, children)
+        }
+        _ => StyledText::None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_msg(date: &str, from: &str, body: &str) -> Message {
+        let text = StyledText::Styled(
+            tuiColor::White,
+            vec![
+                StyledText::Styled(tuiColor::Rgb(0, 255, 0), vec![StyledText::Text(body.to_owned())]),
+                StyledText::Text(" - ".to_owned()),
+                StyledText::Styled(tuiColor::Rgb(255, 0, 0), vec![StyledText::Text(from.to_owned())]),
+            ],
+        );
+        Message::new(Some(1), MessageType::UserMsg, date.to_owned(), None, text)
+    }
+
+    #[test]
+    fn record_and_reload() {
+        let history = History::open_in_memory("[M] ", "%m-%d %H:%M:%S").unwrap();
+        let msgs = vec![
+            user_msg("10-18 12:00:02", "bob", "second"),
+            user_msg("10-18 12:00:01", "alice", "first"),
+        ];
+        history.record(&msgs).unwrap();
+        // Same messages seen again on the next refresh are not duplicated
+        history.record(&msgs).unwrap();
+        let loaded = history.load(RELOAD_LIMIT).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].date, "10-18 12:00:02");
+        assert_eq!(loaded[0].text, msgs[0].text);
+        assert_eq!(loaded[1].text, msgs[1].text);
+
+        history.mark_deleted(&msgs[1..]).unwrap();
+        let loaded = history.load(1).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert!(!loaded[0].deleted);
+        assert!(history.load(2).unwrap()[1].deleted);
+
+        // The same text at the same time from someone else is another message
+        history.record(&[user_msg("10-18 12:00:01", "carol", "first")]).unwrap();
+        assert_eq!(history.load(RELOAD_LIMIT).unwrap().len(), 3);
+
+        // A date that does not fit the format is stored once, and can be deleted
+        let odd = [user_msg("yesterday", "dave", "hello")];
+        history.record(&odd).unwrap();
+        history.record(&odd).unwrap();
+        assert_eq!(history.load(RELOAD_LIMIT).unwrap().len(), 4);
+        history.mark_deleted(&odd).unwrap();
+        assert!(history.load(1).unwrap()[0].deleted);
+    }
+
+    #[test]
+    fn full_dates() {
+        let at = |s: &str| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap();
+        let now = at("2025-01-01 00:10:00");
+        let fmt = "%m-%d %H:%M:%S";
+        assert_eq!(sent_at("01-01 00:09:00", fmt, now), Some(at("2025-01-01 00:09:00")));
+        // Seen just after new year
+        assert_eq!(sent_at("12-31 23:59:00", fmt, now), Some(at("2024-12-31 23:59:00")));
+        // Only the time, from yesterday
+        assert_eq!(sent_at("23:59", "%H:%M", now), Some(at("2024-12-31 23:59:00")));
+        assert_eq!(sent_at("00:05", "%H:%M", now), Some(at("2025-01-01 00:05:00")));
+        assert_eq!(sent_at("yesterday", fmt, now), None);
+    }
+
+    #[test]
+    fn search() {
+        let history = History::open_in_memory("[M] ", "%m-%d %H:%M:%S").unwrap();
+        history
+            .record(&[
+                user_msg("10-18 12:00:03", "carol", "nothing to see"),
+                user_msg("10-18 12:00:02", "bob", "selling cheap stuff"),
+                user_msg("10-18 12:00:01", "alice", "who is selling?"),
+            ])
+            .unwrap();
+        let search = |q: &str| -> Vec<String> {
+            let query = search::SearchQuery::parse(q).unwrap();
+            history
+                .search(&query, SEARCH_LIMIT)
+                .unwrap()
+                .iter()
+                .map(|m| m.date.clone())
+                .collect()
+        };
+        assert_eq!(search("selling"), vec!["10-18 12:00:02", "10-18 12:00:01"]);
+        assert_eq!(search("selling from:BOB"), vec!["10-18 12:00:02"]);
+        assert_eq!(search("re:\\?$"), vec!["10-18 12:00:01"]);
+        assert_eq!(search("since:2000-01-01 see").len(), 1);
+        assert!(search("until:2000-01-01").is_empty());
+        // Filtered on the day the messages were sent
+        let year = sent_at("10-18 12:00:01", "%m-%d %H:%M:%S", Utc::now().naive_utc()).unwrap().year();
+        assert_eq!(search(&format!("since:{0}-10-18 until:{0}-10-18 selling", year)).len(), 2);
+        assert!(search(&format!("since:{}-10-19", year)).is_empty());
+        assert!(search(&format!("until:{}-10-17", year)).is_empty());
+        assert!(search("AND").is_empty());
+    }
+}
@@ -0,0 +1,85 @@
+use chrono::NaiveDate;
+use regex::Regex;
+
+/// A parsed `/search` command.
+///
+/// `/search from:alice to:bob since:2024-10-01 until:2024-10-18 re:^hi some words`
+#[derive(Debug, Default)]
+pub struct SearchQuery {
+    /// Full-text terms, all of them must match.
+    pub terms: Vec<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// Inclusive, compared with the day the message was sent, in the time zone of the server.
+    pub since: Option<NaiveDate>,
+    pub until: Option<NaiveDate>,
+    pub regex: Option<Regex>,
+}
+
+impl SearchQuery {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let mut query = SearchQuery::default();
+        for word in input.split_whitespace() {
+            match word.split_once(':') {
+                Some(("from", v)) if !v.is_empty() => query.from = Some(v.to_owned()),
+                Some(("to", v)) if !v.is_empty() => query.to = Some(v.to_owned()),
+                Some(("since", v)) => query.since = Some(parse_day(v)?),
+                Some(("until", v)) => query.until = Some(parse_day(v)?),
+                Some(("re", v)) => {
+                    query.regex = Some(Regex::new(v).map_err(|e| format!("invalid regex: {}", e))?)
+                }
+                _ => query.terms.push(word.to_owned()),
+            }
+        }
+        if query.is_empty() {
+            return Err("empty search".to_owned());
+        }
+        Ok(query)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+            && self.from.is_none()
+            && self.to.is_none()
+            && self.since.is_none()
+            && self.until.is_none()
+            && self.regex.is_none()
+    }
+
+    // Each term is quoted so fts5 operators typed by the user are taken literally
+    pub(super) fn fts_query(&self) -> Option<String> {
+        if self.terms.is_empty() {
+            return None;
+        }
+        Some(
+            self.terms
+                .iter()
+                .map(|t| format!("\"{}\"", t.replace('"', "\"\"")))
+                .collect::<Vec<_>>()
+                .join(" "),
+        )
+    }
+}
+
+fn parse_day(s: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|_| format!("invalid date {}, expected YYYY-MM-DD", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_query() {
+        let q = SearchQuery::parse("from:alice to:bob since:2024-10-01 re:^hi hello \"world").unwrap();
+        assert_eq!(q.from.as_deref(), Some("alice"));
+        assert_eq!(q.to.as_deref(), Some("bob"));
+        assert_eq!(q.since, NaiveDate::from_ymd_opt(2024, 10, 1));
+        assert_eq!(q.until, None);
+        assert!(q.regex.unwrap().is_match("hi there"));
+        assert_eq!(q.terms, vec!["hello", "\"world"]);
+        assert!(SearchQuery::parse("   ").is_err());
+        assert!(SearchQuery::parse("since:yesterday").is_err());
+        assert!(SearchQuery::parse("re:(").is_err());
+    }
+}
@@ -0,0 +1,577 @@
+//! Local IRC server for the chat session (`--irc-listen 127.0.0.1:6667`).
+//!
+//! The room is `#chat`, posts for members only are `#members`, and pms are queries.
+//! Admins are ops (`@`), staff half-ops (`%`) and members voiced (`+`).
+//! `PRIVMSG`, `KICK`, `NICK`, `IGNORE` and `UNIGNORE` are sent like the same TUI commands.
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use bhcli::lechatphp::{get_message, Message, MessageType, Users, SEND_TO_ALL, SEND_TO_MEMBERS};
+use crossbeam_channel::{Sender, TrySendError};
+
+use crate::audit::{AuditEntry, AuditKind};
+use crate::PostType;
+
+pub const CHAT: &str = "#chat";
+pub const MEMBERS: &str = "#members";
+/// Prefix of the lines coming from the gateway itself.
+const SERVER: &str = "bhcli";
+/// Lines waiting to be written to a client, more are dropped when it does not keep up.
+const CLIENT_BUFFER: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Rank {
+    Guest,
+    Member,
+    Staff,
+    Admin,
+}
+
+impl Rank {
+    fn mode(&self) -> Option<char> {
+        match self {
+            Rank::Admin => Some('o'),
+            Rank::Staff => Some('h'),
+            Rank::Member => Some('v'),
+            Rank::Guest => None,
+        }
+    }
+
+    fn prefix(&self) -> &'static str {
+        match self {
+            Rank::Admin => "@",
+            Rank::Staff => "%",
+            Rank::Member => "+",
+            Rank::Guest => "",
+        }
+    }
+}
+
+// Irc nicknames cannot have spaces
+fn irc_nick(name: &str) -> String {
+    name.replace(' ', "_")
+}
+
+fn ranks(users: &Users) -> HashMap<String, Rank> {
+    let lists = [
+        (&users.guests, Rank::Guest),
+        (&users.members, Rank::Member),
+        (&users.staff, Rank::Staff),
+        (&users.admin, Rank::Admin),
+    ];
+    let mut ranks = HashMap::new();
+    for (list, rank) in lists {
+        for (_, name) in list {
+            ranks.insert(irc_nick(name), rank);
+        }
+    }
+    ranks
+}
+
+// Who is in `channel`, guests cannot see the posts for members
+fn channel_users(channel: &str, ranks: &HashMap<String, Rank>) -> HashMap<String, Rank> {
+    let min = if channel == MEMBERS { Rank::Member } else { Rank::Guest };
+    ranks.iter().filter(|(_, r)| **r >= min).map(|(n, r)| (n.clone(), *r)).collect()
+}
+
+/// `:prefix COMMAND param :trailing` split in the command, uppercase, and its parameters.
+fn parse(line: &str) -> Option<(String, Vec<String>)> {
+    let mut rest = line.trim_end_matches(['\r', '\n']);
+    if rest.starts_with(':') {
+        rest = rest.split_once(' ')?.1;
+    }
+    let (rest, trailing) = match rest.split_once(" :") {
+        Some((rest, trailing)) => (rest, Some(trailing)),
+        None => (rest, None),
+    };
+    let mut params = rest.split_whitespace().map(str::to_owned);
+    let command = params.next()?.to_uppercase();
+    let mut params: Vec<String> = params.collect();
+    params.extend(trailing.map(str::to_owned));
+    Some((command, params))
+}
+
+struct Client {
+    conn: usize,
+    channels: HashSet<String>,
+    out: Sender<String>,
+}
+
+/// Clients of the gateway, and the users online to tell them who joined or left.
+#[derive(Default)]
+pub struct Irc {
+    clients: Mutex<Vec<Client>>,
+    next_conn: AtomicUsize,
+    ranks: Mutex<HashMap<String, Rank>>,
+    // Chat nickname of every irc one
+    names: Mutex<HashMap<String, String>>,
+}
+
+// Handles of the session the commands act on
+struct Session {
+    tx: Sender<PostType>,
+    username: String,
+    /// Needed in `PASS` when set
+    password: Option<String>,
+}
+
+// What a connection did so far
+struct Conn {
+    id: usize,
+    nick: String,
+    registered: bool,
+    pass: Option<String>,
+    user: bool,
+    out: Sender<String>,
+}
+
+impl Irc {
+    /// Listen on `addr` in the background, anyone who can connect can post and kick.
+    /// Addresses other than loopback ones are refused unless a password is set.
+    pub fn serve(
+        &'static self,
+        addr: &str,
+        tx: Sender<PostType>,
+        username: &str,
+        password: Option<String>,
+    ) -> io::Result<()> {
+        if password.is_none() && !addr.to_socket_addrs()?.all(|a| a.ip().is_loopback()) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "listening on a non-loopback address needs --irc-password",
+            ));
+        }
+        let listener = TcpListener::bind(addr)?;
+        let session = Arc::new(Session {
+            tx,
+            username: username.to_owned(),
+            password,
+        });
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::error!("irc: {}", err);
+                        continue;
+                    }
+                };
+                let Ok(writer) = stream.try_clone() else {
+                    continue;
+                };
+                let session = Arc::clone(&session);
+                thread::spawn(move || self.handle_client(BufReader::new(stream), writer, &session));
+            }
+        });
+        Ok(())
+    }
+
+    fn handle_client(&self, reader: impl BufRead, mut writer: impl Write + Send + 'static, session: &Session) {
+        let (out, out_rx) = crossbeam_channel::bounded::<String>(CLIENT_BUFFER);
+        let handle = thread::spawn(move || {
+            for line in out_rx {
+                if write!(writer, "{}\r\n", line).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut conn = Conn {
+            id: self.next_conn.fetch_add(1, Ordering::Relaxed),
+            nick: "*".to_owned(),
+            registered: false,
+            pass: None,
+            user: false,
+            out,
+        };
+        for line in reader.lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            let Some((command, params)) = parse(&line) else {
+                continue;
+            };
+            if !self.handle_command(&mut conn, &command, &params, session) {
+                break;
+            }
+        }
+        self.clients.lock().unwrap().retain(|c| c.conn != conn.id);
+        drop(conn);
+        let _ = handle.join();
+    }
+
+    // False once the client is gone
+    fn handle_command(&self, conn: &mut Conn, command: &str, params: &[String], session: &Session) -> bool {
+        let reply = |line: String| conn.out.send(line).is_ok();
+        let numeric = |code: &str, text: &str| format!(":{} {} {} {}", SERVER, code, conn.nick, text);
+        let param = |i: usize| params.get(i).map(String::as_str).unwrap_or_default();
+        match command {
+            "CAP" if param(0).eq_ignore_ascii_case("LS") => reply(format!(":{} CAP * LS :", SERVER)),
+            "CAP" => true,
+            "PING" => reply(format!(":{} PONG {} :{}", SERVER, SERVER, param(0))),
+            "QUIT" => {
+                let _ = reply(format!("ERROR :Closing link ({})", conn.nick));
+                false
+            }
+            "PASS" if !conn.registered => {
+                conn.pass = Some(param(0).to_owned());
+                true
+            }
+            "NICK" if !conn.registered => {
+                conn.nick = param(0).to_owned();
+                self.register(conn, session)
+            }
+            "USER" if !conn.registered => {
+                conn.user = true;
+                self.register(conn, session)
+            }
+            _ if !conn.registered => reply(numeric("451", ":You have not registered")),
+            "PASS" | "USER" => reply(numeric("462", ":You may not reregister")),
+            "NICK" => {
+                let nickname = param(0);
+                if nickname.is_empty() {
+                    return reply(numeric("431", ":No nickname given"));
+                }
+                let nickname = irc_nick(nickname);
+                if !self.post(conn, session, PostType::NewNickname(nickname.clone())) {
+                    return false;
+                }
+                let renamed = reply(format!(":{} NICK :{}", conn.nick, nickname));
+                conn.nick = nickname;
+                renamed
+            }
+            "JOIN" => param(0).split(',').all(|channel| self.join(conn, channel)),
+            "PART" => {
+                for channel in param(0).split(',') {
+                    self.part(conn, channel);
+                }
+                true
+            }
+            "NAMES" => self.names(conn, param(0)),
+            "MODE" if param(0).starts_with('#') => reply(numeric("324", &format!("{} +nt", param(0)))),
+            "MODE" => reply(numeric("221", "+i")),
+            "WHO" => reply(numeric("315", &format!("{} :End of WHO list", param(0)))),
+            "PRIVMSG" | "NOTICE" => {
+                let (target, text) = (param(0), param(1));
+                let text = match text.strip_prefix("\x01ACTION ") {
+                    Some(action) => format!("/me {}", action.trim_end_matches('\x01')),
+                    None => text.to_owned(),
+                };
+                let to = match target {
+                    CHAT => SEND_TO_ALL.to_owned(),
+                    MEMBERS => SEND_TO_MEMBERS.to_owned(),
+                    nick => self.chat_nick(nick),
+                };
+                text.is_empty() || self.post(conn, session, PostType::Post(text, Some(to)))
+            }
+            "KICK" => {
+                let user = self.chat_nick(param(1));
+                let actor = format!("irc:{}", conn.nick);
+                crate::audit(AuditEntry::new(AuditKind::Kick, &user.to_lowercase(), "manual", param(2), "", &actor));
+                self.post(conn, session, PostType::Kick(param(2).to_owned(), user))
+            }
+            "IGNORE" => self.post(conn, session, PostType::Ignore(self.chat_nick(param(0)))),
+            "UNIGNORE" => self.post(conn, session, PostType::Unignore(self.chat_nick(param(0)))),
+            _ => reply(numeric("421", &format!("{} :Unknown command", command))),
+        }
+    }
+
+    // Once NICK and USER are in, the client gets our chat nickname whatever it asked for
+    fn register(&self, conn: &mut Conn, session: &Session) -> bool {
+        if conn.nick == "*" || !conn.user {
+            return true;
+        }
+        if session.password.is_some() && conn.pass != session.password {
+            let _ = conn.out.send("ERROR :Wrong password".to_owned());
+            return false;
+        }
+        let nick = irc_nick(&session.username);
+        let welcome = [
+            format!(":{} 001 {} :Welcome to the chat, {}", SERVER, conn.nick, nick),
+            format!(":{} 005 {} CHANTYPES=# PREFIX=(ohv)@%+ :are supported", SERVER, conn.nick),
+            format!(":{} 422 {} :MOTD File is missing", SERVER, conn.nick),
+        ];
+        for line in welcome {
+            if conn.out.send(line).is_err() {
+                return false;
+            }
+        }
+        if conn.nick != nick {
+            let _ = conn.out.send(format!(":{} NICK :{}", conn.nick, nick));
+            conn.nick = nick;
+        }
+        conn.registered = true;
+        self.clients.lock().unwrap().push(Client {
+            conn: conn.id,
+            channels: HashSet::new(),
+            out: conn.out.clone(),
+        });
+        self.join(conn, CHAT)
+    }
+
+    fn join(&self, conn: &Conn, channel: &str) -> bool {
+        if channel != CHAT && channel != MEMBERS {
+            return conn.out.send(format!(":{} 403 {} {} :No such channel", SERVER, conn.nick, channel)).is_ok();
+        }
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.iter_mut().find(|c| c.conn == conn.id) {
+            client.channels.insert(channel.to_owned());
+        }
+        drop(clients);
+        conn.out.send(format!(":{} JOIN {}", conn.nick, channel)).is_ok() && self.names(conn, channel)
+    }
+
+    fn part(&self, conn: &Conn, channel: &str) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.iter_mut().find(|c| c.conn == conn.id) {
+            if client.channels.remove(channel) {
+                let _ = conn.out.send(format!(":{} PART {}", conn.nick, channel));
+            }
+        }
+    }
+
+    fn names(&self, conn: &Conn, channel: &str) -> bool {
+        let mut names: Vec<(Rank, String)> = channel_users(channel, &self.ranks.lock().unwrap())
+            .into_iter()
+            .map(|(nick, rank)| (rank, nick))
+            .collect();
+        names.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        for chunk in names.chunks(20) {
+            let names: Vec<String> = chunk.iter().map(|(rank, nick)| format!("{}{}", rank.prefix(), nick)).collect();
+            let line = format!(":{} 353 {} = {} :{}", SERVER, conn.nick, channel, names.join(" "));
+            if conn.out.send(line).is_err() {
+                return false;
+            }
+        }
+        conn.out.send(format!(":{} 366 {} {} :End of /NAMES list", SERVER, conn.nick, channel)).is_ok()
+    }
+
+    // False once the client is gone
+    fn post(&self, conn: &Conn, session: &Session, post: PostType) -> bool {
+        // Sent by the post thread like everything typed in the TUI
+        session.tx.send(post).is_ok()
+            || conn.out.send(format!(":{} NOTICE {} :not connected", SERVER, conn.nick)).is_ok()
+    }
+
+    // The chat nickname of an irc one, they only differ by spaces
+    fn chat_nick(&self, nick: &str) -> String {
+        self.names.lock().unwrap().get(nick).cloned().unwrap_or_else(|| nick.to_owned())
+    }
+
+    // Lines for the clients in `channel`, or every client when it is None
+    fn send(&self, channel: Option<&str>, lines: &[String]) {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|c| {
+            if channel.is_some_and(|ch| !c.channels.contains(ch)) {
+                return true;
+            }
+            lines.iter().all(|line| match c.out.try_send(line.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("irc: client {} is too slow, line dropped", c.conn);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            })
+        });
+    }
+
+    /// A new chat message, our own posts are already shown by the irc client.
+    pub fn message(&self, msg: &Message, members_tag: &str, username: &str) {
+        if self.clients.lock().unwrap().is_empty() || msg.hide {
+            return;
+        }
+        let nick = irc_nick(username);
+        let (channel, line) = match get_message(&msg.text, members_tag) {
+            Some((from, _, _)) if from == username => return,
+            Some((from, Some(to), text)) if to == username => {
+                (None, format!(":{} PRIVMSG {} :{}", irc_nick(&from), nick, text))
+            }
+            Some((from, Some(to), text)) => {
+                let text = format!("[{} to {}] {}", from, to, text);
+                (Some(CHAT), format!(":{} NOTICE {} :{}", SERVER, CHAT, text))
+            }
+            Some((from, None, text)) => {
+                let (channel, text) = match text.strip_prefix(members_tag) {
+                    Some(text) => (MEMBERS, text.to_owned()),
+                    None => (CHAT, text),
+                };
+                (Some(channel), format!(":{} PRIVMSG {} :{}", irc_nick(&from), channel, text))
+            }
+            None if msg.typ == MessageType::SysMsg => {
+                (Some(CHAT), format!(":{} NOTICE {} :{}", SERVER, CHAT, msg.text.text()))
+            }
+            None => return,
+        };
+        self.send(channel, &[line]);
+    }
+
+    /// Joins, leaves and rank changes since the last list, as seen from `username`.
+    pub fn users(&self, users: &Users, username: &str) {
+        let ranks = ranks(users);
+        *self.names.lock().unwrap() = [&users.admin, &users.staff, &users.members, &users.guests]
+            .into_iter()
+            .flat_map(|list| list.iter().map(|(_, name)| (irc_nick(name), name.clone())))
+            .collect();
+        let last = std::mem::replace(&mut *self.ranks.lock().unwrap(), ranks.clone());
+        if last == ranks {
+            return;
+        }
+        let nick = irc_nick(username);
+        for channel in [CHAT, MEMBERS] {
+            let (before, after) = (channel_users(channel, &last), channel_users(channel, &ranks));
+            let mut lines = vec![];
+            let mut users: Vec<(&String, &Rank)> = after.iter().collect();
+            users.sort();
+            for (user, rank) in users {
+                if *user == nick {
+                    continue;
+                }
+                let was = before.get(user);
+                if was.is_none() {
+                    lines.push(format!(":{} JOIN {}", user, channel));
+                }
+                let old_mode = was.and_then(Rank::mode);
+                if old_mode != rank.mode() {
+                    if let Some(mode) = old_mode {
+                        lines.push(format!(":{} MODE {} -{} {}", SERVER, channel, mode, user));
+                    }
+                    if let Some(mode) = rank.mode() {
+                        lines.push(format!(":{} MODE {} +{} {}", SERVER, channel, mode, user));
+                    }
+                }
+            }
+            let mut left: Vec<&String> = before.keys().filter(|u| !after.contains_key(*u) && **u != nick).collect();
+            left.sort();
+            for user in left {
+                lines.push(format!(":{} PART {}", user, channel));
+            }
+            if !lines.is_empty() {
+                self.send(Some(channel), &lines);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bhcli::lechatphp::StyledText;
+    use std::net::TcpStream;
+    use std::time::Duration;
+    use tui::style::Color;
+
+    fn users(guests: &[&str], members: &[&str], admins: &[&str]) -> Users {
+        let list = |names: &[&str]| names.iter().map(|n| (Color::White, n.to_string())).collect();
+        Users {
+            guests: list(guests),
+            members: list(members),
+            admin: list(admins),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_lines() {
+        let parsed = parse(":nick!u@h PRIVMSG #chat :hello there\r\n").unwrap();
+        assert_eq!(parsed, ("PRIVMSG".to_owned(), vec!["#chat".to_owned(), "hello there".to_owned()]));
+        let parsed = parse("kick #chat bob :too loud").unwrap();
+        assert_eq!(parsed, ("KICK".to_owned(), vec!["#chat".to_owned(), "bob".to_owned(), "too loud".to_owned()]));
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn non_loopback_needs_password() {
+        let irc: &'static Irc = Box::leak(Box::default());
+        let (tx, _posts) = crossbeam_channel::unbounded();
+        let err = irc.serve("0.0.0.0:0", tx.clone(), "dantca", None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(irc.serve("127.0.0.1:0", tx.clone(), "dantca", None).is_ok());
+        assert!(irc.serve("0.0.0.0:0", tx, "dantca", Some("secret".to_owned())).is_ok());
+    }
+
+    #[test]
+    fn session_over_irc() {
+        let irc: &'static Irc = Box::leak(Box::default());
+        irc.users(&users(&["carol"], &["bob"], &["dantca"]), "dantca");
+        let (tx, posts) = crossbeam_channel::unbounded();
+        let session = Session {
+            tx,
+            username: "dantca".to_owned(),
+            password: Some("secret".to_owned()),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let writer = server.try_clone().unwrap();
+        let handle = thread::spawn(move || irc.handle_client(BufReader::new(server), writer, &session));
+        let mut lines = BufReader::new(client.try_clone().unwrap()).lines().map(|l| l.unwrap());
+        let mut read_until = |end: &str| -> Vec<String> {
+            let mut read = vec![];
+            for line in lines.by_ref() {
+                let done = line.contains(end);
+                read.push(line);
+                if done {
+                    break;
+                }
+            }
+            read
+        };
+
+        write!(client, "PASS secret\r\nNICK me\r\nUSER me 0 * :me\r\n").unwrap();
+        let welcome = read_until(" 366 ");
+        assert!(welcome.contains(&":me NICK :dantca".to_owned()), "{:?}", welcome);
+        assert!(welcome.contains(&":dantca JOIN #chat".to_owned()));
+        assert!(welcome.contains(&":bhcli 353 dantca = #chat :@dantca +bob carol".to_owned()));
+
+        for line in [
+            "PRIVMSG #chat :hi all",
+            "PRIVMSG #members :members only",
+            "PRIVMSG bob :\x01ACTION waves\x01",
+            "KICK #chat carol :spam",
+            "IGNORE carol",
+            "NICK dan",
+        ] {
+            write!(client, "{}\r\n", line).unwrap();
+        }
+        read_until("NICK :dan");
+        let posts: Vec<String> = posts.try_iter().map(|p| format!("{:?}", p)).collect();
+        assert_eq!(
+            posts,
+            vec![
+                r#"Post("hi all", Some("s *"))"#,
+                r#"Post("members only", Some("s ?"))"#,
+                r#"Post("/me waves", Some("bob"))"#,
+                r#"Kick("spam", "carol")"#,
+                r#"Ignore("carol")"#,
+                r#"NewNickname("dan")"#,
+            ]
+        );
+        let kicks = crate::AUDIT.lock().unwrap().kicks(100).unwrap();
+        assert!(kicks.iter().any(|k| (k.target.as_str(), k.reason.as_str(), k.actor.as_str()) == ("carol", "spam", "irc:dantca")));
+
+        irc.users(&users(&["dave"], &["carol"], &["dantca"]), "dantca");
+        assert_eq!(read_until("PART #chat"), vec![":bhcli MODE #chat +v carol", ":dave JOIN #chat", ":bob PART #chat"]);
+
+        let text = StyledText::Styled(
+            Color::White,
+            vec![
+                StyledText::Styled(Color::Green, vec![StyledText::Text("hello @dantca".to_owned())]),
+                StyledText::Text(" - ".to_owned()),
+                StyledText::Styled(Color::Red, vec![StyledText::Text("carol".to_owned())]),
+            ],
+        );
+        let msg = Message::new(None, MessageType::UserMsg, "01-01 10:00:00".to_owned(), None, text);
+        irc.message(&msg, "[M] ", "dantca");
+        assert_eq!(read_until("PRIVMSG"), vec![":carol PRIVMSG #chat :hello @dantca"]);
+
+        write!(client, "QUIT\r\n").unwrap();
+        assert_eq!(read_until("ERROR"), vec!["ERROR :Closing link (dan)"]);
+        handle.join().unwrap();
+        assert!(irc.clients.lock().unwrap().is_empty());
+    }
+}
@@ -1,60 +1,162 @@
-use std::collections::HashMap;
 use base64::{engine::general_purpose, Engine as _};
 use image::{DynamicImage, GenericImageView, Rgba};
 use lazy_static::lazy_static;
 
-const B64_PREFIX: &'static str = "R0lGODlhCAAOAIAAAAAAAAAAACH5BAgAAAAALAAAAAAIAA4AgAQCBPz+/AI";
-const ALPHABET1: &'static str = "abdcefgh1ijkImnpoqrstyQuvwxzABCDEGJKMNHLORPFSTlUVWXYZ023456789";
+const ALPHABET1: &str = "abdcefgh1ijkImnpoqrstyQuvwxzABCDEGJKMNHLORPFSTlUVWXYZ023456789";
 const LETTER_WIDTH: u32 = 8;
 const LETTER_HEIGHT: u32 = 14;
 const NB_CHARS: u32 = 5;
 const LEFT_PADDING: u32 = 5;
 const TOP_PADDING: u32 = 7;
+// The font is 9 pixels wide, its last column is always empty
+const LETTER_SPACING: u32 = LETTER_WIDTH + 1;
+// Difficulty 1 and 2, difficulty 3 captchas are 150x200
+const CAPTCHA_WIDTH: u32 = 55;
+const CAPTCHA_HEIGHT: u32 = 24;
+
+type Letter = [u8; LETTER_HEIGHT as usize];
+
+// Rows of the letters of ALPHABET1 in the font used by le-chat-php (gd font 5),
+// the most significant bit is the leftmost pixel
+const GLYPHS: [Letter; ALPHABET1.len()] = [
+    [0x00, 0x00, 0x00, 0x00, 0x3e, 0x63, 0x03, 0x7f, 0xc3, 0xc7, 0x7b, 0x00, 0x00, 0x00], // a
+    [0x00, 0xc0, 0xc0, 0xc0, 0xdc, 0xe6, 0xc3, 0xc3, 0xc3, 0xe6, 0xdc, 0x00, 0x00, 0x00], // b
+    [0x00, 0x03, 0x03, 0x03, 0x3b, 0x67, 0xc3, 0xc3, 0xc3, 0x67, 0x3b, 0x00, 0x00, 0x00], // d
+    [0x00, 0x00, 0x00, 0x00, 0x3e, 0x63, 0xc0, 0xc0, 0xc0, 0x63, 0x3e, 0x00, 0x00, 0x00], // c
+    [0x00, 0x00, 0x00, 0x00, 0x3c, 0x66, 0xc3, 0xff, 0xc0, 0x63, 0x3e, 0x00, 0x00, 0x00], // e
+    [0x00, 0x1e, 0x33, 0x33, 0x30, 0x30, 0xfc, 0x30, 0x30, 0x30, 0x30, 0x00, 0x00, 0x00], // f
+    [0x00, 0x00, 0x00, 0x00, 0x7d, 0xc7, 0xc6, 0xc6, 0x7c, 0xc0, 0x7e, 0xc3, 0x7e, 0x00], // g
+    [0x00, 0xc0, 0xc0, 0xc0, 0xdc, 0xe6, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0x00, 0x00, 0x00], // h
+    [0x00, 0x18, 0x38, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x7e, 0x00, 0x00, 0x00], // 1
+    [0x00, 0x18, 0x18, 0x00, 0x38, 0x18, 0x18, 0x18, 0x18, 0x18, 0x7e, 0x00, 0x00, 0x00], // i
+    [0x00, 0x06, 0x06, 0x00, 0x0e, 0x06, 0x06, 0x06, 0x06, 0x06, 0xc6, 0xc6, 0x7c, 0x00], // j
+    [0x00, 0x60, 0x60, 0x60, 0x66, 0x6c, 0x78, 0x78, 0x6c, 0x66, 0x63, 0x00, 0x00, 0x00], // k
+    [0x00, 0x7e, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x7e, 0x00, 0x00, 0x00], // I
+    [0x00, 0x00, 0x00, 0x00, 0xb6, 0xdb, 0xdb, 0xdb, 0xdb, 0xdb, 0xdb, 0x00, 0x00, 0x00], // m
+    [0x00, 0x00, 0x00, 0x00, 0xdc, 0xe6, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0x00, 0x00, 0x00], // n
+    [0x00, 0x00, 0x00, 0x00, 0xdc, 0xe6, 0xc3, 0xc3, 0xc3, 0xe6, 0xdc, 0xc0, 0xc0, 0x00], // p
+    [0x00, 0x00, 0x00, 0x00, 0x3c, 0x66, 0xc3, 0xc3, 0xc3, 0x66, 0x3c, 0x00, 0x00, 0x00], // o
+    [0x00, 0x00, 0x00, 0x00, 0x3b, 0x67, 0xc3, 0xc3, 0xc3, 0x67, 0x3b, 0x03, 0x03, 0x00], // q
+    [0x00, 0x00, 0x00, 0x00, 0xde, 0x73, 0x60, 0x60, 0x60, 0x60, 0x60, 0x00, 0x00, 0x00], // r
+    [0x00, 0x00, 0x00, 0x00, 0x7e, 0xc3, 0xc0, 0x7e, 0x03, 0xc3, 0x7e, 0x00, 0x00, 0x00], // s
+    [0x00, 0x00, 0x30, 0x30, 0xfc, 0x30, 0x30, 0x30, 0x30, 0x33, 0x1e, 0x00, 0x00, 0x00], // t
+    [0x00, 0x00, 0x00, 0x00, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0x67, 0x3b, 0x83, 0x7e, 0x00], // y
+    [0x00, 0x3c, 0x66, 0xc3, 0xc3, 0xc3, 0xc3, 0xdb, 0xcf, 0x66, 0x3d, 0x00, 0x00, 0x00], // Q
+    [0x00, 0x00, 0x00, 0x00, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0x67, 0x3b, 0x00, 0x00, 0x00], // u
+    [0x00, 0x00, 0x00, 0x00, 0xc3, 0xc3, 0x66, 0x66, 0x3c, 0x3c, 0x18, 0x00, 0x00, 0x00], // v
+    [0x00, 0x00, 0x00, 0x00, 0xc3, 0xc3, 0xdb, 0xdb, 0xdb, 0xff, 0x66, 0x00, 0x00, 0x00], // w
+    [0x00, 0x00, 0x00, 0x00, 0xc3, 0x66, 0x3c, 0x18, 0x3c, 0x66, 0xc3, 0x00, 0x00, 0x00], // x
+    [0x00, 0x00, 0x00, 0x00, 0x7e, 0x06, 0x0c, 0x18, 0x30, 0x60, 0x7e, 0x00, 0x00, 0x00], // z
+    [0x00, 0x18, 0x3c, 0x66, 0xc3, 0xc3, 0xc3, 0xff, 0xc3, 0xc3, 0xc3, 0x00, 0x00, 0x00], // A
+    [0x00, 0xfc, 0xc6, 0xc3, 0xc6, 0xfc, 0xc6, 0xc3, 0xc3, 0xc6, 0xfc, 0x00, 0x00, 0x00], // B
+    [0x00, 0x3e, 0x63, 0xc1, 0xc0, 0xc0, 0xc0, 0xc0, 0xc1, 0x63, 0x3e, 0x00, 0x00, 0x00], // C
+    [0x00, 0xfc, 0xc6, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xc6, 0xfc, 0x00, 0x00, 0x00], // D
+    [0x00, 0xfe, 0xc0, 0xc0, 0xc0, 0xfc, 0xc0, 0xc0, 0xc0, 0xc0, 0xfe, 0x00, 0x00, 0x00], // E
+    [0x00, 0x3e, 0x63, 0xc0, 0xc0, 0xc0, 0xc7, 0xc3, 0xc3, 0x63, 0x3e, 0x00, 0x00, 0x00], // G
+    [0x00, 0x1e, 0x06, 0x06, 0x06, 0x06, 0x06, 0x06, 0x46, 0x6c, 0x38, 0x00, 0x00, 0x00], // J
+    [0x00, 0xc3, 0xc6, 0xcc, 0xd8, 0xf0, 0xf0, 0xd8, 0xcc, 0xc6, 0xc3, 0x00, 0x00, 0x00], // K
+    [0x00, 0xc3, 0xe7, 0xff, 0xdb, 0xdb, 0xdb, 0xc3, 0xc3, 0xc3, 0xc3, 0x00, 0x00, 0x00], // M
+    [0x00, 0xc3, 0xe3, 0xf3, 0xf3, 0xdb, 0xdb, 0xcf, 0xc7, 0xc7, 0xc3, 0x00, 0x00, 0x00], // N
+    [0x00, 0xc3, 0xc3, 0xc3, 0xc3, 0xff, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0x00, 0x00, 0x00], // H
+    [0x00, 0xc0, 0xc0, 0xc0, 0xc0, 0xc0, 0xc0, 0xc0, 0xc0, 0xc0, 0xfe, 0x00, 0x00, 0x00], // L
+    [0x00, 0x3c, 0x66, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0x66, 0x3c, 0x00, 0x00, 0x00], // O
+    [0x00, 0xfe, 0xc3, 0xc3, 0xc3, 0xfe, 0xf8, 0xcc, 0xc6, 0xc3, 0xc3, 0x00, 0x00, 0x00], // R
+    [0x00, 0xfe, 0xc3, 0xc3, 0xc3, 0xfe, 0xc0, 0xc0, 0xc0, 0xc0, 0xc0, 0x00, 0x00, 0x00], // P
+    [0x00, 0xff, 0xc0, 0xc0, 0xc0, 0xfc, 0xc0, 0xc0, 0xc0, 0xc0, 0xc0, 0x00, 0x00, 0x00], // F
+    [0x00, 0x7e, 0xc3, 0xc0, 0xc0, 0x7e, 0x03, 0x03, 0x03, 0xc3, 0x7e, 0x00, 0x00, 0x00], // S
+    [0x00, 0xff, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x00, 0x00, 0x00], // T
+    [0x00, 0x38, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3c, 0x00, 0x00, 0x00], // l
+    [0x00, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0x66, 0x3c, 0x00, 0x00, 0x00], // U
+    [0x00, 0xc3, 0xc3, 0xc3, 0x66, 0x66, 0x66, 0x3c, 0x3c, 0x18, 0x18, 0x00, 0x00, 0x00], // V
+    [0x00, 0xc3, 0xc3, 0xc3, 0xc3, 0xdb, 0xdb, 0xdb, 0xff, 0xe7, 0xc3, 0x00, 0x00, 0x00], // W
+    [0x00, 0xc3, 0xc3, 0x66, 0x3c, 0x18, 0x18, 0x3c, 0x66, 0xc3, 0xc3, 0x00, 0x00, 0x00], // X
+    [0x00, 0xc3, 0xc3, 0x66, 0x3c, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x00, 0x00, 0x00], // Y
+    [0x00, 0xfe, 0x06, 0x06, 0x0c, 0x18, 0x30, 0x60, 0xc0, 0xc0, 0xfe, 0x00, 0x00, 0x00], // Z
+    [0x00, 0x18, 0x3c, 0x66, 0xc3, 0xc3, 0xc3, 0xc3, 0x66, 0x3c, 0x18, 0x00, 0x00, 0x00], // 0
+    [0x00, 0x3c, 0x66, 0xc3, 0x03, 0x06, 0x0c, 0x18, 0x30, 0x60, 0xff, 0x00, 0x00, 0x00], // 2
+    [0x00, 0x7c, 0xc6, 0x03, 0x06, 0x1c, 0x06, 0x03, 0x03, 0xc6, 0x7c, 0x00, 0x00, 0x00], // 3
+    [0x00, 0x06, 0x0e, 0x1e, 0x36, 0x66, 0xc6, 0xff, 0x06, 0x06, 0x06, 0x00, 0x00, 0x00], // 4
+    [0x00, 0xfe, 0xc0, 0xc0, 0xdc, 0xe6, 0x03, 0x03, 0xc3, 0x66, 0x3c, 0x00, 0x00, 0x00], // 5
+    [0x00, 0x3c, 0x66, 0xc2, 0xc0, 0xdc, 0xe6, 0xc3, 0xc3, 0x66, 0x3c, 0x00, 0x00, 0x00], // 6
+    [0x00, 0xff, 0x03, 0x03, 0x06, 0x0c, 0x18, 0x30, 0x60, 0xc0, 0xc0, 0x00, 0x00, 0x00], // 7
+    [0x00, 0x3c, 0x66, 0xc3, 0x66, 0x3c, 0x66, 0xc3, 0xc3, 0x66, 0x3c, 0x00, 0x00, 0x00], // 8
+    [0x00, 0x3c, 0x66, 0xc3, 0xc3, 0x67, 0x3b, 0x03, 0x43, 0x66, 0x3c, 0x00, 0x00, 0x00], // 9
+];
 
 lazy_static! {
-    static ref B64_MAP: HashMap<char, &'static str> = HashMap::from([
-        // ... (unchanged B64_MAP content)
-    ]);
     static ref RED_COLOR: Rgba<u8> = Rgba::from([204, 2, 4, 255]);
     static ref ON_COLOR: Rgba<u8> = Rgba::from([252, 254, 252, 255]);
 }
 
-// Komentar: Struktur model dihapus karena tidak ada implementasi tch dan ndarray
-struct CaptchaSolverModel;
+pub fn solve_b64(b64_str: &str) -> Option<String> {
+    let img_dec = general_purpose::STANDARD.decode(b64_str.strip_prefix("data:image/gif;base64,")?).ok()?;
+    let img = image::load_from_memory(&img_dec).ok()?;
+    solve(&img)
+}
 
-impl CaptchaSolverModel {
-    fn new() -> Self {
-        Self
+/// Answer of a difficulty 1 or 2 captcha, None for anything else.
+pub fn solve(img: &DynamicImage) -> Option<String> {
+    // The letters of difficulty 3 are scattered and linked by a red line
+    if img.width() != CAPTCHA_WIDTH || img.height() != CAPTCHA_HEIGHT || count_red_px(img) > 0 {
+        return None;
     }
+    solve_difficulty1(img).or_else(|| solve_difficulty2(img))
+}
 
-    fn solve(&self, _img: &DynamicImage) -> String {
-        // Komentar: Implementasi sementara, mengembalikan string kosong
-        String::new()
-    }
+// No noise, every letter is one of the glyphs
+fn solve_difficulty1(img: &DynamicImage) -> Option<String> {
+    letters(img)
+        .map(|letter| glyphs().find(|(_, glyph)| *glyph == letter).map(|(c, _)| c))
+        .collect()
 }
 
-pub fn solve_b64(b64_str: &str) -> Option<String> {
-    let img_dec = general_purpose::STANDARD.decode(b64_str.strip_prefix("data:image/gif;base64,")?).ok()?;
-    let img = image::load_from_memory(&img_dec).ok()?;
-    Some(CaptchaSolverModel::new().solve(&img))
+// White lines and dots only add pixels: the letter is the glyph that has none of its
+// pixels missing and leaves the fewest extra ones
+fn solve_difficulty2(img: &DynamicImage) -> Option<String> {
+    letters(img)
+        .map(|letter| {
+            glyphs()
+                .filter(|(_, glyph)| count_px(glyph, &letter) == 0)
+                .min_by_key(|(_, glyph)| count_px(&letter, glyph))
+                .map(|(c, _)| c)
+        })
+        .collect()
 }
 
-// Komentar: Fungsi-fungsi berikut tidak lagi digunakan dalam pendekatan CNN+RNN
-// tetapi tetap dipertahankan untuk referensi atau penggunaan alternatif
+fn glyphs() -> impl Iterator<Item = (char, Letter)> {
+    ALPHABET1.chars().zip(GLYPHS)
+}
 
-fn solve_difficulty2(img: &DynamicImage) -> Option<String> {
-    // Komentar: Implementasi sementara, mengembalikan None
-    None
+fn letters(img: &DynamicImage) -> impl Iterator<Item = Letter> + '_ {
+    (0..NB_CHARS).map(move |i| {
+        let left = LEFT_PADDING + i * LETTER_SPACING;
+        let mut letter = [0; LETTER_HEIGHT as usize];
+        for (y, row) in letter.iter_mut().enumerate() {
+            for x in 0..LETTER_WIDTH {
+                if is_on_color(img.get_pixel(left + x, TOP_PADDING + y as u32)) {
+                    *row |= 0x80 >> x;
+                }
+            }
+        }
+        letter
+    })
 }
 
-// ... (other unchanged functions)
+// Pixels of `a` that are not in `b`
+fn count_px(a: &Letter, b: &Letter) -> u32 {
+    a.iter().zip(b).map(|(a, b)| (a & !b).count_ones()).sum()
+}
 
 fn count_red_px(img: &DynamicImage) -> usize {
     img.pixels()
         .filter(|(_, _, c)| is_red_color(*c))
         .count()
-}   
+}
 
 fn is_red_color(color: Rgba<u8>) -> bool {
     color == *RED_COLOR
 }
+
+fn is_on_color(color: Rgba<u8>) -> bool {
+    color == *ON_COLOR
+}
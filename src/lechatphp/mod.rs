@@ -1,23 +1,105 @@
-use crate::{
-    trim_newline, CAPTCHA_USED_ERR, CAPTCHA_WG_ERR, KICKED_ERR, LANG,
-    NICKNAME_ERR, REG_ERR, SERVER_DOWN_500_ERR, SERVER_DOWN_ERR, SESSION_RGX, UNKNOWN_ERR,
-};
-use base64::engine::general_purpose;
-use base64::Engine;
+use anyhow::anyhow;
+use colors_transform::{Color, Rgb};
 use http::StatusCode;
-use regex::Regex;
 use image::DynamicImage;
+use lazy_static::lazy_static;
+use regex::Regex;
 
+use reqwest::blocking::multipart;
 use reqwest::blocking::Client;
 use select::document::Document;
-use select::predicate::{And, Attr, Name};
+use select::predicate::{And, Attr, Class, Name};
 use std::fmt::{Display, Formatter};
-use std::io::Write;
-use std::process::{Command, Stdio};
 use std::time::Duration;
-use std::{error, fs, io, thread};
+use std::{error, io, thread};
+use tui::style::Color as tuiColor;
 
 pub mod captcha;
+pub mod solvers;
+
+use solvers::{Captcha, CaptchaSolver};
+
+pub const LANG: &str = "en";
+pub const SEND_TO_ALL: &str = "s *";
+pub const SEND_TO_MEMBERS: &str = "s ?";
+pub const SEND_TO_STAFFS: &str = "s %";
+pub const SEND_TO_ADMINS: &str = "s _";
+pub const SERVER_DOWN_500_ERR: &str = "500 Internal Server Error, server down";
+pub const SERVER_DOWN_ERR: &str = "502 Bad Gateway, server down";
+pub const KICKED_ERR: &str = "You have been kicked";
+pub const REG_ERR: &str = "This nickname is a registered member";
+pub const NICKNAME_ERR: &str = "Invalid nickname";
+pub const CAPTCHA_WG_ERR: &str = "Wrong Captcha";
+pub const CAPTCHA_USED_ERR: &str = "Captcha already used or timed out";
+pub const CAPTCHA_FMT_ERR: &str = "Unexpected captcha image, expected a gif or png";
+pub const CAPTCHA_UNSOLVED_ERR: &str = "No captcha solver gave an answer";
+pub const WAITROOM_CANCELLED_ERR: &str = "Left the waitroom";
+pub const ADMISSION_DENIED_ERR: &str = "Admission to the chat denied";
+pub const UNKNOWN_ERR: &str = "Unknown error";
+
+type FormParams = Vec<(&'static str, String)>;
+
+lazy_static! {
+    static ref SESSION_RGX: Regex = Regex::new(r#"session=([^&]+)"#).unwrap();
+    static ref COLOR_RGX: Regex = Regex::new(r#"color:\s*([#\w]+)\s*;"#).unwrap();
+    static ref REFRESH_RGX: Regex = Regex::new(r#"(?:(\d+)\s*;\s*)?URL=(.+)"#).unwrap();
+    static ref ADMISSION_RGX: Regex = Regex::new(r#"in (\d+) seconds"#).unwrap();
+}
+
+// Refresh interval of the waitroom when the server does not send one
+const DEFAULT_WAITROOM_REFRESH: Duration = Duration::from_secs(10);
+// How often the waitroom callback of `login` is called
+const WAITROOM_TICK: Duration = Duration::from_secs(1);
+
+/// Where we are in the waitroom, given to the callback of `login` while waiting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Waitroom {
+    /// Interval between refreshes asked by the server
+    pub refresh: Duration,
+    /// Time left before the next refresh
+    pub next_refresh: Duration,
+    /// Refreshes done so far
+    pub refreshes: usize,
+    /// When the server lets us in by itself, None when a moderator has to
+    pub admission_in: Option<Duration>,
+    /// What the waitroom page says
+    pub notice: String,
+}
+
+impl Waitroom {
+    // From the `refresh` header and the waitroom page
+    fn parse(refresh_header: &str, body: &str, refreshes: usize) -> Option<(Self, String)> {
+        let captures = REFRESH_RGX.captures(refresh_header)?;
+        let refresh = captures
+            .get(1)
+            .and_then(|d| d.as_str().parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_WAITROOM_REFRESH);
+        let doc = Document::from(body);
+        let notice = doc
+            .find(Name("p"))
+            .map(|p| p.text().trim().to_owned())
+            .find(|t| !t.is_empty())
+            .unwrap_or_default();
+        let admission_in = ADMISSION_RGX
+            .captures(&notice)
+            .and_then(|c| c[1].parse().ok())
+            .map(Duration::from_secs);
+        let waitroom = Self {
+            refresh,
+            next_refresh: refresh,
+            refreshes,
+            admission_in,
+            notice,
+        };
+        Some((waitroom, captures[2].trim().to_owned()))
+    }
+}
+
+/// Waitroom callback of `login` that waits as long as the server asks.
+pub fn keep_waiting(_: &Waitroom) -> bool {
+    true
+}
 
 #[derive(Debug)]
 pub enum LoginErr {
@@ -25,6 +107,10 @@ pub enum LoginErr {
     ServerDown500Err,
     CaptchaUsedErr,
     CaptchaWgErr,
+    CaptchaFmtErr,
+    CaptchaUnsolvedErr,
+    WaitroomCancelledErr,
+    AdmissionDeniedErr,
     RegErr,
     NicknameErr,
     KickedErr,
@@ -45,6 +131,10 @@ impl Display for LoginErr {
             LoginErr::ServerDown500Err => SERVER_DOWN_500_ERR.to_owned(),
             LoginErr::CaptchaUsedErr => CAPTCHA_USED_ERR.to_owned(),
             LoginErr::CaptchaWgErr => CAPTCHA_WG_ERR.to_owned(),
+            LoginErr::CaptchaFmtErr => CAPTCHA_FMT_ERR.to_owned(),
+            LoginErr::CaptchaUnsolvedErr => CAPTCHA_UNSOLVED_ERR.to_owned(),
+            LoginErr::WaitroomCancelledErr => WAITROOM_CANCELLED_ERR.to_owned(),
+            LoginErr::AdmissionDeniedErr => ADMISSION_DENIED_ERR.to_owned(),
             LoginErr::RegErr => REG_ERR.to_owned(),
             LoginErr::NicknameErr => NICKNAME_ERR.to_owned(),
             LoginErr::KickedErr => KICKED_ERR.to_owned(),
@@ -57,6 +147,473 @@ impl Display for LoginErr {
 
 impl error::Error for LoginErr {}
 
+/// Errors returned by the `Session` methods once we are logged in.
+#[derive(Debug)]
+pub enum SessionErr {
+    /// The server no longer recognizes our session, a new login is needed.
+    Expired,
+    /// The page did not contain something we expected (form field, message id...).
+    Parse(&'static str),
+    Reqwest(reqwest::Error),
+    Io(io::Error),
+}
+
+impl From<reqwest::Error> for SessionErr {
+    fn from(value: reqwest::Error) -> Self {
+        SessionErr::Reqwest(value)
+    }
+}
+
+impl From<io::Error> for SessionErr {
+    fn from(value: io::Error) -> Self {
+        SessionErr::Io(value)
+    }
+}
+
+impl Display for SessionErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionErr::Expired => write!(f, "session expired"),
+            SessionErr::Parse(what) => write!(f, "{} not found", what),
+            SessionErr::Reqwest(e) => write!(f, "{}", e),
+            SessionErr::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for SessionErr {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum MessageType {
+    UserMsg,
+    SysMsg,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Message {
+    pub id: Option<usize>,
+    pub typ: MessageType,
+    pub date: String,
+    pub upload_link: Option<String>,
+    pub text: StyledText,
+    pub deleted: bool, // Either or not a message was deleted on the chat
+    pub hide: bool,    // Either ot not to hide a specific message
+}
+
+impl Message {
+    pub fn new(
+        id: Option<usize>,
+        typ: MessageType,
+        date: String,
+        upload_link: Option<String>,
+        text: StyledText,
+    ) -> Self {
+        Self {
+            id,
+            typ,
+            date,
+            upload_link,
+            text,
+            deleted: false,
+            hide: false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum StyledText {
+    Styled(tuiColor, Vec<StyledText>),
+    Text(String),
+    None,
+}
+
+impl StyledText {
+    fn walk<F>(&self, mut clb: F)
+    where
+        F: FnMut(&StyledText),
+    {
+        let mut v: Vec<&StyledText> = vec![self];
+        while let Some(e) = v.pop() {
+            clb(e);
+            if let StyledText::Styled(_, children) = e {
+                v.extend(children);
+            }
+        }
+    }
+
+    pub fn text(&self) -> String {
+        let mut s = String::new();
+        self.walk(|n| {
+            if let StyledText::Text(t) = n {
+                s += t;
+            }
+        });
+        s
+    }
+
+    // Return a vector of each text parts & what color it should be
+    pub fn colored_text(&self) -> Vec<(tuiColor, String)> {
+        let mut out: Vec<(tuiColor, String)> = vec![];
+        let mut v: Vec<(tuiColor, &StyledText)> = vec![(tuiColor::White, self)];
+        while let Some((el_color, e)) = v.pop() {
+            match e {
+                StyledText::Styled(tui_color, children) => {
+                    for child in children {
+                        v.push((*tui_color, child));
+                    }
+                }
+                StyledText::Text(t) => {
+                    out.push((el_color, t.to_owned()));
+                }
+                StyledText::None => {}
+            }
+        }
+        out
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct Users {
+    pub admin: Vec<(tuiColor, String)>,
+    pub staff: Vec<(tuiColor, String)>,
+    pub members: Vec<(tuiColor, String)>,
+    pub guests: Vec<(tuiColor, String)>,
+}
+
+impl Users {
+    pub fn all(&self) -> Vec<&(tuiColor, String)> {
+        let mut out = Vec::new();
+        out.extend(&self.admin);
+        out.extend(&self.staff);
+        out.extend(&self.members);
+        out.extend(&self.guests);
+        out
+    }
+
+    pub fn is_guest(&self, name: &str) -> bool {
+        self.guests.iter().any(|(_, username)| username == name)
+    }
+}
+
+/// Everything we get out of a single `action=view` page.
+pub struct View {
+    pub messages: Vec<Message>,
+    pub users: Users,
+    /// Number of unread messages in the inbox, `None` if the notification is not displayed.
+    pub inbox_count: Option<usize>,
+    /// Number of "has been kicked." system messages still visible.
+    pub kicked_count: usize,
+    /// Most recent user that "has joined the chat." among the visible messages
+    /// (the view lists the newest messages first).
+    pub last_joined: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InboxMessage {
+    pub date: String,
+    pub from: String,
+    pub to: String,
+    pub text: String,
+}
+
+/// Which message(s) to delete with `Session::delete`.
+#[derive(Debug, Clone)]
+pub enum Delete {
+    /// Our own last message.
+    Last,
+    /// All our own messages.
+    All,
+    /// Any message (needs moderation rights), identified by its date and text.
+    Message(String, String),
+}
+
+/// Changes to apply with `Session::profile`, `None` fields are left untouched.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileUpdate {
+    pub nickname: Option<String>,
+    pub color: Option<String>,
+    pub ignore: Option<String>,
+    pub unignore: Option<String>,
+}
+
+/// A logged in le-chat-php session.
+#[derive(Clone)]
+pub struct Session {
+    client: Client,
+    base_url: String,
+    page_php: String,
+    id: String,
+}
+
+impl Session {
+    /// Reuse an already known session id without going through the login form.
+    pub fn new(client: &Client, base_url: &str, page_php: &str, id: &str) -> Self {
+        Self {
+            client: client.clone(),
+            base_url: base_url.to_owned(),
+            page_php: page_php.to_owned(),
+            id: id.to_owned(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn full_url(&self) -> String {
+        format!("{}/{}", &self.base_url, &self.page_php)
+    }
+
+    pub fn view(&self) -> Result<View, SessionErr> {
+        let url = format!(
+            "{}?action=view&session={}&lang={}",
+            self.full_url(),
+            self.id,
+            LANG
+        );
+        let resp_text = self.client.get(url).send()?.text()?;
+        let resp_text = resp_text.replace("<br>", "\n");
+        let doc = Document::from(resp_text.as_str());
+        let messages = extract_messages(&doc).map_err(|_| SessionErr::Expired)?;
+        let (kicked_count, last_joined) = count_kicked_users(&doc);
+        Ok(View {
+            messages,
+            users: extract_users(&doc),
+            inbox_count: extract_inbox_count(&doc),
+            kicked_count,
+            last_joined,
+        })
+    }
+
+    pub fn post(&self, msg: &str, send_to: Option<&str>) -> Result<(), SessionErr> {
+        let (mut params, postid) = self.post_form()?;
+        params.extend(vec![
+            ("action", "post".to_owned()),
+            ("postid", postid),
+            ("multi", "on".to_owned()),
+            ("message", msg.to_owned()),
+            ("sendto", send_to.unwrap_or(SEND_TO_ALL).to_owned()),
+        ]);
+        self.client.post(self.full_url()).form(&params).send()?;
+        Ok(())
+    }
+
+    pub fn kick(&self, username: &str, msg: &str) -> Result<(), SessionErr> {
+        let (mut params, postid) = self.post_form()?;
+        params.extend(vec![
+            ("action", "post".to_owned()),
+            ("postid", postid),
+            ("message", msg.to_owned()),
+            ("sendto", username.to_owned()),
+            ("kick", "kick".to_owned()),
+            ("what", "purge".to_owned()),
+        ]);
+        self.client.post(self.full_url()).form(&params).send()?;
+        Ok(())
+    }
+
+    pub fn upload(&self, file_path: &str, send_to: &str, msg: &str) -> Result<(), SessionErr> {
+        let (params, postid) = self.post_form()?;
+        let mut form = multipart::Form::new();
+        for (k, v) in params {
+            form = form.text(k, v);
+        }
+        let form = form
+            .text("action", "post")
+            .text("postid", postid)
+            .text("message", msg.to_owned())
+            .text("sendto", send_to.to_owned())
+            .text("what", "purge")
+            .file("file", file_path)?;
+        self.client.post(self.full_url()).multipart(form).send()?;
+        Ok(())
+    }
+
+    pub fn delete(&self, what: Delete) -> Result<(), SessionErr> {
+        let (mut params, _) = self.post_form()?;
+        match what {
+            Delete::Last => params.extend(vec![
+                ("action", "delete".to_owned()),
+                ("sendto", "".to_owned()),
+                ("what", "last".to_owned()),
+            ]),
+            Delete::All => params.extend(vec![
+                ("action", "delete".to_owned()),
+                ("sendto", SEND_TO_ALL.to_owned()),
+                ("confirm", "yes".to_owned()),
+                ("what", "all".to_owned()),
+            ]),
+            Delete::Message(date, text) => return self.delete_message(params, &date, &text),
+        }
+        self.client.post(self.full_url()).form(&params).send()?;
+        Ok(())
+    }
+
+    pub fn profile(&self, update: ProfileUpdate) -> Result<(), SessionErr> {
+        let (mut params, _) = self.post_form()?;
+        self.set_profile_base_info(&mut params)?;
+        params.extend(vec![
+            ("do", "save".to_owned()),
+            ("timestamps", "on".to_owned()),
+        ]);
+        if let Some(color) = update.color {
+            params.push(("colour", color));
+        }
+        if let Some(nickname) = update.nickname {
+            params.push(("newnickname", nickname));
+        }
+        if let Some(username) = update.ignore {
+            params.push(("ignore", username));
+        }
+        if let Some(username) = update.unignore {
+            params.push(("unignore", username));
+        }
+        self.client.post(self.full_url()).form(&params).send()?;
+        Ok(())
+    }
+
+    pub fn inbox(&self) -> Result<Vec<InboxMessage>, SessionErr> {
+        let (mut params, _) = self.post_form()?;
+        params.push(("action", "inbox".to_owned()));
+        let inbox_content = self
+            .client
+            .post(self.full_url())
+            .form(&params)
+            .send()?
+            .text()?;
+        Ok(extract_inbox_messages(&inbox_content))
+    }
+
+    pub fn clean_inbox(&self) -> Result<(), SessionErr> {
+        let (mut params, _) = self.post_form()?;
+        params.extend(vec![
+            ("action", "inbox".to_owned()),
+            ("do", "clean".to_owned()),
+        ]);
+        let inbox_content = self
+            .client
+            .get(self.full_url())
+            .query(&params)
+            .send()?
+            .text()?;
+        let doc = Document::from(inbox_content.as_str());
+        for checkbox in doc.find(Attr("name", "mid[]")) {
+            if let Some(value) = checkbox.attr("value") {
+                params.push(("mid[]", value.to_owned()));
+            }
+        }
+        self.client.post(self.full_url()).form(&params).send()?;
+        Ok(())
+    }
+
+    pub fn logout(&self) -> Result<(), SessionErr> {
+        let params = [("action", "logout"), ("session", &self.id), ("lang", LANG)];
+        self.client.post(self.full_url()).form(&params).send()?;
+        Ok(())
+    }
+
+    // Load the post form, return the base params (lang/nc/session) and the postid
+    fn post_form(&self) -> Result<(FormParams, String), SessionErr> {
+        let url = format!("{}?action=post&session={}", self.full_url(), &self.id);
+        let resp_text = self.client.get(url).send()?.text()?;
+        let doc = Document::from(resp_text.as_str());
+        let nc_value = doc
+            .find(Attr("name", "nc"))
+            .next()
+            .and_then(|nc| nc.attr("value"))
+            .ok_or(SessionErr::Parse("nc"))?
+            .to_owned();
+        let postid_value = doc
+            .find(Attr("name", "postid"))
+            .next()
+            .and_then(|postid| postid.attr("value"))
+            .ok_or(SessionErr::Parse("postid"))?
+            .to_owned();
+        let params = vec![
+            ("lang", LANG.to_owned()),
+            ("nc", nc_value),
+            ("session", self.id.clone()),
+        ];
+        Ok((params, postid_value))
+    }
+
+    fn set_profile_base_info(
+        &self,
+        params: &mut FormParams,
+    ) -> Result<(), SessionErr> {
+        params.extend(vec![("action", "profile".to_owned())]);
+        let profile_resp_txt = self
+            .client
+            .post(self.full_url())
+            .form(&params)
+            .send()?
+            .text()?;
+        let doc = Document::from(profile_resp_txt.as_str());
+        for id in ["bold", "italic", "small"] {
+            let checkbox = doc.find(Attr("id", id)).next().ok_or(SessionErr::Parse(id))?;
+            if checkbox.attr("checked").is_some() {
+                params.push((id, "on".to_owned()));
+            }
+        }
+        let font_select = doc
+            .find(Attr("name", "font"))
+            .next()
+            .ok_or(SessionErr::Parse("font"))?;
+        let font = font_select
+            .find(Name("option"))
+            .find(|el| el.attr("selected").is_some())
+            .and_then(|el| el.attr("value"));
+        params.push(("font", font.unwrap_or("").to_owned()));
+        Ok(())
+    }
+
+    fn delete_message(
+        &self,
+        mut params: FormParams,
+        date: &str,
+        text: &str,
+    ) -> Result<(), SessionErr> {
+        params.extend(vec![
+            ("action", "admin".to_owned()),
+            ("do", "clean".to_owned()),
+            ("what", "choose".to_owned()),
+        ]);
+        let clean_resp_txt = self
+            .client
+            .post(self.full_url())
+            .form(&params)
+            .send()?
+            .text()?;
+        let doc = Document::from(clean_resp_txt.as_str());
+        let nc_value = doc
+            .find(Attr("name", "nc"))
+            .next()
+            .and_then(|nc| nc.attr("value"))
+            .ok_or(SessionErr::Parse("nc"))?
+            .to_owned();
+        let msgs = extract_messages(&doc).map_err(|_| SessionErr::Parse("messages"))?;
+        if let Some(msg) = msgs
+            .iter()
+            .find(|m| m.date == date && m.text.text() == text)
+        {
+            let msg_id = msg.id.ok_or(SessionErr::Parse("msg id"))?;
+            params.extend(vec![
+                ("nc", nc_value),
+                ("what", "selected".to_owned()),
+                ("mid[]", format!("{}", msg_id)),
+            ]);
+            self.client.post(self.full_url()).form(&params).send()?;
+        }
+        Ok(())
+    }
+}
+
+/// `waitroom` is called every second while the server keeps us in the waitroom, false leaves it.
+#[allow(clippy::too_many_arguments)]
 pub fn login(
     client: &Client,
     base_url: &str,
@@ -64,8 +621,9 @@ pub fn login(
     username: &str,
     password: &str,
     color: &str,
-    manual_captcha: bool,
-) -> Result<String, LoginErr> {
+    solvers: &[Box<dyn CaptchaSolver>],
+    waitroom: &mut dyn FnMut(&Waitroom) -> bool,
+) -> Result<Session, LoginErr> {
     // Get login page
     let login_url = format!("{}/{}", &base_url, &page_php);
     let resp = client.get(&login_url).send()?;
@@ -88,118 +646,13 @@ pub fn login(
         .find(And(Name("input"), Attr("name", "challenge")))
         .next()
     {
-        let captcha_value = captcha_node.attr("value").unwrap();
-        let captcha_img = doc.find(Name("img")).next().unwrap().attr("src").unwrap();
-
-        let mut captcha_input = String::new();
-        if manual_captcha {
-            // Attempt to strip the appropriate prefix based on the MIME type
-            let base64_str =
-                if let Some(base64) = captcha_img.strip_prefix("data:image/png;base64,") {
-                    base64
-                } else if let Some(base64) = captcha_img.strip_prefix("data:image/gif;base64,") {
-                    base64
-                } else {
-                    panic!("Unexpected captcha image format. Expected PNG or GIF.");
-                };
-
-            // Decode the base64 string into binary image data
-            let img_decoded = general_purpose::STANDARD.decode(base64_str).unwrap();
-
-            let img = image::load_from_memory(&img_decoded).unwrap();
-            let img_buf = image::imageops::resize(
-                &img,
-                img.width() * 4,
-                img.height() * 4,
-                image::imageops::FilterType::Nearest,
-            );
-            // Save captcha as file on disk
-            img_buf.save("captcha.gif").unwrap();
-
-            // Pilihan untuk menyelesaikan captcha
-            println!("choice your methode captcha view:");
-            println!("1. Terminal (ASCII)");
-            println!("2. Sxiv");
-            print!("choice option: ");
-            io::stdout().flush().unwrap();
-            let mut choice = String::new();
-            io::stdin().read_line(&mut choice).unwrap();
-            let choice = choice.trim();
-
-            match choice {
-                "1" => {
-                    // Menampilkan captcha di terminal secara langsung (ASCII)
-                    println!("Captcha:");
-                    let img_ascii = image_to_ascii(&img, 80, 40);
-                    println!("{}", img_ascii);
-                    
-                    // Prompt untuk memasukkan captcha
-                    print!("Masukkan captcha: ");
-                    io::stdout().flush().unwrap();
-                    io::stdin().read_line(&mut captcha_input).unwrap();
-                    trim_newline(&mut captcha_input);
-                },
-                "2" => {
-                    let mut sxiv_process = Command::new("sxiv")
-                        .arg("captcha.gif")
-                        .stdout(Stdio::null())
-                        .stderr(Stdio::null())
-                        .spawn()
-                        .expect("Failed to open image with sxiv");
-
-                    // Prompt the user to enter the CAPTCHA
-                    print!("Please enter the CAPTCHA: ");
-                    io::stdout().flush().unwrap();
-                    io::stdin().read_line(&mut captcha_input).unwrap();
-                    trim_newline(&mut captcha_input);
-
-                    // Close the sxiv window
-                    sxiv_process.kill().expect("Failed to close sxiv");
-
-                    println!("Captcha input: {}", captcha_input);
-                },
-                _ => {
-                    println!("Pilihan tidak valid. Menggunakan metode terminal (ASCII).");
-                    // Menampilkan captcha di terminal secara langsung
-                    println!("Captcha:");
-                    let img_ascii = image_to_ascii(&img, 80, 40);
-                    println!("{}", img_ascii);
-                    
-                    print!("Masukkan captcha: ");
-                    io::stdout().flush().unwrap();
-                    io::stdin().read_line(&mut captcha_input).unwrap();
-                    trim_newline(&mut captcha_input);
-                }
-            }
-
-            // Menyimpan captcha yang baru dimasukkan
-        } else {
-            // Menyelesaikan captcha secara otomatis
-            captcha_input = match captcha::solve_b64(captcha_img) {
-                Some(solved_captcha) => solved_captcha,
-                None => {
-                    // Jika gagal menyelesaikan captcha secara otomatis, coba gunakan captcha sebelumnya
-                    if let Ok(previous_captcha) = fs::read_to_string("previous_captcha.txt") {
-                        println!("Menggunakan captcha sebelumnya: {}", previous_captcha.trim());
-                        previous_captcha.trim().to_string()
-                    } else {
-                        // Jika tidak ada captcha sebelumnya, gunakan metode manual
-                        println!("Gagal menyelesaikan captcha secara otomatis dan tidak ada captcha sebelumnya. Menggunakan metode manual.");
-                        let img = image::load_from_memory(&general_purpose::STANDARD.decode(captcha_img.split(',').last().unwrap()).unwrap()).unwrap();
-                        println!("Captcha:");
-                        let img_ascii = image_to_ascii(&img, 80, 40);
-                        println!("{}", img_ascii);
-                        
-                        print!("Masukkan captcha: ");
-                        io::stdout().flush().unwrap();
-                        let mut manual_input = String::new();
-                        io::stdin().read_line(&mut manual_input).unwrap();
-                        trim_newline(&mut manual_input);
-                        manual_input
-                    }
-                }
-            };
-        }
+        let Some(captcha_value) = captcha_node.attr("value") else {
+            log::error!("captcha challenge without value: {}", resp);
+            return Err(LoginErr::UnknownErr);
+        };
+        let captcha_img = doc.find(Name("img")).next().and_then(|img| img.attr("src")).unwrap_or_default();
+        let captcha = Captcha::from_data_url(captcha_img).ok_or(LoginErr::CaptchaFmtErr)?;
+        let captcha_input = solvers::solve(solvers, &captcha).ok_or(LoginErr::CaptchaUnsolvedErr)?;
 
         params.extend(vec![
             ("challenge", captcha_value.to_owned()),
@@ -208,39 +661,46 @@ pub fn login(
     }
 
     let mut resp = client.post(&login_url).form(&params).send()?;
-    match resp.status() {
-        StatusCode::BAD_GATEWAY => return Err(LoginErr::ServerDownErr),
-        StatusCode::INTERNAL_SERVER_ERROR => return Err(LoginErr::ServerDown500Err),
-        _ => {}
-    }
-
-    let mut refresh_header = resp
-        .headers()
-        .get("refresh")
-        .map(|v| v.to_str().unwrap())
-        .unwrap_or("");
-    while refresh_header != "" {
-        let rgx = Regex::new(r#"URL=(.+)"#).unwrap();
-        let refresh_url = format!(
-            "{}{}",
-            base_url,
-            rgx.captures(&refresh_header)
-                .unwrap()
-                .get(1)
-                .unwrap()
-                .as_str()
-        );
-        println!("waitroom enabled, wait 10sec");
-        thread::sleep(Duration::from_secs(10));
-        resp = client.get(refresh_url.clone()).send()?;
-        refresh_header = resp
-            .headers()
-            .get("refresh")
-            .map(|v| v.to_str().unwrap())
-            .unwrap_or("");
+    let mut refreshes = 0;
+    loop {
+        match resp.status() {
+            StatusCode::BAD_GATEWAY => return Err(LoginErr::ServerDownErr),
+            StatusCode::INTERNAL_SERVER_ERROR => return Err(LoginErr::ServerDown500Err),
+            _ => {}
+        }
+        let Some(refresh_header) = resp.headers().get("refresh") else {
+            break;
+        };
+        let refresh_header = refresh_header.to_str().unwrap_or_default().to_owned();
+        let body = resp.text()?;
+        let Some((mut state, refresh_url)) = Waitroom::parse(&refresh_header, &body, refreshes) else {
+            log::error!("unexpected waitroom refresh header: {}", refresh_header);
+            return Err(LoginErr::UnknownErr);
+        };
+        loop {
+            if !waitroom(&state) {
+                return Err(LoginErr::WaitroomCancelledErr);
+            }
+            if state.next_refresh.is_zero() {
+                break;
+            }
+            let tick = state.next_refresh.min(WAITROOM_TICK);
+            thread::sleep(tick);
+            state.next_refresh -= tick;
+        }
+        resp = client.get(format!("{}{}", base_url, refresh_url)).send()?;
+        refreshes += 1;
     }
 
     let mut resp = resp.text()?;
+    // Sent away by a moderator while in the waitroom
+    if refreshes > 0 {
+        let doc = Document::from(resp.as_str());
+        if let Some(body) = doc.find(Name("body")).next().filter(|b| b.attr("class") == Some("error")) {
+            log::error!("admission denied: {}", body.text().trim());
+            return Err(LoginErr::AdmissionDeniedErr);
+        }
+    }
     if resp.contains(CAPTCHA_USED_ERR) {
         return Err(LoginErr::CaptchaUsedErr);
     } else if resp.contains(CAPTCHA_WG_ERR) {
@@ -263,8 +723,11 @@ pub fn login(
                 return Err(LoginErr::UnknownErr);
             } else if body_class == "failednotice" {
                 log::error!("failed logins: {}", body.text());
-                let nc = doc.find(Attr("name", "nc")).next().unwrap();
-                let nc_value = nc.attr("value").unwrap().to_owned();
+                let Some(nc_value) = doc.find(Attr("name", "nc")).next().and_then(|nc| nc.attr("value")) else {
+                    log::error!("failed logins notice without nc: {}", resp);
+                    return Err(LoginErr::UnknownErr);
+                };
+                let nc_value = nc_value.to_owned();
                 let params: Vec<(&str, String)> = vec![
                     ("lang", LANG.to_owned()),
                     ("nc", nc_value.to_owned()),
@@ -276,22 +739,21 @@ pub fn login(
         }
     }
 
-    let iframe = match doc.find(Attr("name", "view")).next() {
-        Some(view) => view,
-        None => {
-            fs::write("./dump_login_err.html", resp.as_str()).unwrap();
-            return Err(LoginErr::UnknownErr); // Ubah panic menjadi return Err
-        }
+    let session = doc
+        .find(Attr("name", "view"))
+        .next()
+        .and_then(|iframe| iframe.attr("src"))
+        .and_then(|src| SESSION_RGX.captures(src))
+        .and_then(|captures| captures.get(1));
+    let Some(session) = session else {
+        log::error!("no session in the login page: {}", resp);
+        return Err(LoginErr::UnknownErr);
     };
-    let iframe_src = iframe.attr("src").unwrap();
-
-    let session_captures = SESSION_RGX.captures(iframe_src).unwrap();
-    let session = session_captures.get(1).unwrap().as_str();
-    Ok(session.to_owned())
+    Ok(Session::new(client, base_url, page_php, session.as_str()))
 }
 
 // Fungsi untuk mengubah gambar menjadi ASCII art
-fn image_to_ascii(img: &DynamicImage, width: u32, height: u32) -> String {
+pub(crate) fn image_to_ascii(img: &DynamicImage, width: u32, height: u32) -> String {
     let img = img.resize_exact(width, height, image::imageops::FilterType::Nearest);
     let img = img.to_luma8();
     let mut result = String::new();
@@ -312,15 +774,247 @@ fn image_to_ascii(img: &DynamicImage, width: u32, height: u32) -> String {
     result
 }
 
+fn remove_suffix<'a>(s: &'a str, suffix: &str) -> &'a str {
+    s.strip_suffix(suffix).unwrap_or(s)
+}
 
-pub fn logout(
-    client: &Client,
-    base_url: &str,
-    page_php: &str,
-    session: &str,
-) -> anyhow::Result<()> {
-    let full_url = format!("{}/{}", &base_url, &page_php);
-    let params = [("action", "logout"), ("session", &session), ("lang", LANG)];
-    client.post(&full_url).form(&params).send()?;
-    Ok(())
-}
\ No newline at end of file
+pub fn parse_color(color_str: &str) -> tuiColor {
+    let mut color = tuiColor::White;
+    if color_str == "red" {
+        return tuiColor::Red;
+    }
+    if let Ok(rgb) = Rgb::from_hex_str(color_str) {
+        color = tuiColor::Rgb(
+            rgb.get_red() as u8,
+            rgb.get_green() as u8,
+            rgb.get_blue() as u8,
+        );
+    }
+    color
+}
+
+fn process_node(e: select::node::Node, mut color: tuiColor) -> (StyledText, Option<String>) {
+    match e.data() {
+        select::node::Data::Element(_, _) => {
+            let mut upload_link: Option<String> = None;
+            match e.name() {
+                Some("span") => {
+                    if let Some(style) = e.attr("style") {
+                        if let Some(captures) = COLOR_RGX.captures(style) {
+                            let color_match = captures.get(1).unwrap().as_str();
+                            color = parse_color(color_match);
+                        }
+                    }
+                }
+                Some("font") => {
+                    if let Some(color_str) = e.attr("color") {
+                        color = parse_color(color_str);
+                    }
+                }
+                Some("a") => {
+                    color = tuiColor::White;
+                    if let (Some("attachement"), Some(href)) = (e.attr("class"), e.attr("href")) {
+                        upload_link = Some(href.to_owned());
+                    }
+                }
+                Some("style") => {
+                    return (StyledText::None, None);
+                }
+                _ => {}
+            }
+            let mut children_texts: Vec<StyledText> = vec![];
+            let children = e.children();
+            for child in children {
+                let (st, ul) = process_node(child, color);
+                if ul.is_some() {
+                    upload_link = ul;
+                }
+                children_texts.push(st);
+            }
+            children_texts.reverse();
+            (StyledText::Styled(color, children_texts), upload_link)
+        }
+        select::node::Data::Text(t) => (StyledText::Text(t.to_string()), None),
+        select::node::Data::Comment(_) => (StyledText::None, None),
+    }
+}
+
+pub fn extract_users(doc: &Document) -> Users {
+    let mut users = Users::default();
+
+    if let Some(chatters) = doc.find(Attr("id", "chatters")).next() {
+        if let Some(tr) = chatters.find(Name("tr")).next() {
+            let mut th_count = 0;
+            for e in tr.children() {
+                if let select::node::Data::Element(_, _) = e.data() {
+                    if e.name() == Some("th") {
+                        th_count += 1;
+                        continue;
+                    }
+                    for user_span in e.find(Name("span")) {
+                        if let Some(user_style) = user_span.attr("style") {
+                            if let Some(captures) = COLOR_RGX.captures(user_style) {
+                                if let Some(color_match) = captures.get(1) {
+                                    let color = color_match.as_str().to_owned();
+                                    let tui_color = parse_color(&color);
+                                    let username = user_span.text();
+                                    match th_count {
+                                        1 => users.admin.push((tui_color, username)),
+                                        2 => users.staff.push((tui_color, username)),
+                                        3 => users.members.push((tui_color, username)),
+                                        4 => users.guests.push((tui_color, username)),
+                                        _ => {}
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    users
+}
+
+pub fn extract_messages(doc: &Document) -> anyhow::Result<Vec<Message>> {
+    Ok(doc
+        .find(Attr("id", "messages"))
+        .next()
+        .ok_or_else(|| anyhow!("Gagal mendapatkan div pesan"))?
+        .find(Attr("class", "msg"))
+        .filter_map(|tag| {
+            let id = tag
+                .find(Name("input"))
+                .next()
+                .and_then(|checkbox| checkbox.attr("value"))
+                .and_then(|value| value.parse().ok());
+            let date_node = tag.find(Name("small")).next()?;
+            let msg_span = tag.find(Name("span")).next()?;
+            let date = remove_suffix(&date_node.text(), " - ").to_owned();
+            let typ = match msg_span.attr("class") {
+                Some("usermsg") => MessageType::UserMsg,
+                Some("sysmsg") => MessageType::SysMsg,
+                _ => return None,
+            };
+            let (text, upload_link) = process_node(msg_span, tuiColor::White);
+            Some(Message::new(id, typ, date, upload_link, text))
+        })
+        .collect())
+}
+
+// Ekstrak jumlah pesan dari notifikasi
+fn extract_inbox_count(doc: &Document) -> Option<usize> {
+    doc.find(Attr("id", "notifications"))
+        .next()?
+        .find(Name("form"))
+        .next()?
+        .find(Name("input"))
+        .find(|input| input.attr("type") == Some("submit"))?
+        .attr("value")?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+// Fungsi untuk menghitung jumlah pengguna yang di-kick
+fn count_kicked_users(doc: &Document) -> (usize, Option<String>) {
+    let kicked_count = doc
+        .find(Attr("id", "messages"))
+        .next()
+        .map(|messages| {
+            messages
+                .find(Attr("class", "msg"))
+                .filter(|node| node.text().contains("has been kicked."))
+                .count()
+        })
+        .unwrap_or(0);
+    let new_username = doc.find(Attr("id", "messages")).next().and_then(|messages| {
+        messages
+            .find(Attr("class", "msg"))
+            .filter_map(|node| node.find(Class("sysmsg")).next())
+            .find(|node| node.text().contains("has joined the chat."))
+            .and_then(|node| {
+                let text = node.text();
+                let parts: Vec<&str> = text.split_whitespace().collect();
+                if parts.len() >= 2 {
+                    Some(parts[0].to_string())
+                } else {
+                    None
+                }
+            })
+    });
+    (kicked_count, new_username)
+}
+
+pub fn extract_inbox_messages(inbox_content: &str) -> Vec<InboxMessage> {
+    let doc = Document::from(inbox_content);
+    let mut messages = Vec::new();
+
+    for msg_div in doc.find(Class("msg")) {
+        if let Some(usermsg_span) = msg_div.find(Class("usermsg")).next() {
+            let spans: Vec<_> = usermsg_span.find(Name("span")).collect();
+
+            if spans.len() >= 3 {
+                let date = msg_div
+                    .find(Name("small"))
+                    .next()
+                    .map(|small| small.text().trim().to_string())
+                    .unwrap_or_default();
+                messages.push(InboxMessage {
+                    date,
+                    from: spans[0].text().trim().to_string(),
+                    to: spans[1].text().trim().to_string(),
+                    text: spans[2].text().trim().to_string(),
+                });
+            }
+        }
+    }
+    messages
+}
+
+// Extract "from"/"to"/"message content" from a "StyledText"
+pub fn get_message(root: &StyledText, members_tag: &str) -> Option<(String, Option<String>, String)> {
+    if let StyledText::Styled(_, children) = root {
+        let msg = children.first()?.text();
+        match children.last()? {
+            StyledText::Styled(_, children) => {
+                let from = match children.last()? {
+                    StyledText::Text(t) => t.to_owned(),
+                    _ => return None,
+                };
+                return Some((from, None, msg));
+            }
+            StyledText::Text(t) => {
+                if t == members_tag {
+                    let from = match children.get(children.len() - 2)? {
+                        StyledText::Styled(_, children) => match children.last()? {
+                            StyledText::Text(t) => t.to_owned(),
+                            _ => return None,
+                        },
+                        _ => return None,
+                    };
+                    return Some((from, None, msg));
+                } else if t == "[" {
+                    let from = match children.get(children.len() - 2)? {
+                        StyledText::Styled(_, children) => match children.last()? {
+                            StyledText::Text(t) => t.to_owned(),
+                            _ => return None,
+                        },
+                        _ => return None,
+                    };
+                    let to = match children.get(2)? {
+                        StyledText::Styled(_, children) => match children.last()? {
+                            StyledText::Text(t) => Some(t.to_owned()),
+                            _ => return None,
+                        },
+                        _ => return None,
+                    };
+                    return Some((from, to, msg));
+                }
+            }
+            _ => return None,
+        }
+    }
+    None
+}
@@ -0,0 +1,363 @@
+use std::fmt::{Display, Formatter};
+use std::fs::OpenOptions;
+use std::io::{self, Cursor, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::str::FromStr;
+use std::{error, fs};
+
+use base64::{engine::general_purpose, Engine as _};
+use image::{DynamicImage, GenericImageView, ImageFormat};
+
+use super::captcha;
+use crate::trim_newline;
+
+/// Viewer of `viewer` without a command
+const DEFAULT_VIEWER: &str = "sxiv";
+// Captchas are tiny, the viewer gets them 4 times bigger
+const VIEWER_SCALE: u32 = 4;
+
+/// Captcha of the login page.
+pub struct Captcha {
+    /// `gif` or `png`
+    pub format: &'static str,
+    pub data: Vec<u8>,
+    pub img: DynamicImage,
+}
+
+impl Captcha {
+    /// From the `src` of the captcha image, None when it is not a gif or png data url.
+    pub fn from_data_url(src: &str) -> Option<Self> {
+        let (format, b64) = [("gif", "data:image/gif;base64,"), ("png", "data:image/png;base64,")]
+            .into_iter()
+            .find_map(|(format, prefix)| src.strip_prefix(prefix).map(|b64| (format, b64)))?;
+        let data = general_purpose::STANDARD.decode(b64).ok()?;
+        let img = image::load_from_memory(&data).ok()?;
+        Some(Self { format, data, img })
+    }
+
+    // Temporary file, removed when dropped. Created only for us and never over an existing file,
+    // so a link planted in the temp dir by another user is not followed.
+    fn save(&self, name: &str, img: Option<&DynamicImage>) -> Result<TempFile, SolverErr> {
+        let data = match img {
+            Some(img) => {
+                let format = if self.format == "png" { ImageFormat::Png } else { ImageFormat::Gif };
+                let mut data = Cursor::new(Vec::new());
+                img.write_to(&mut data, format)?;
+                data.into_inner()
+            }
+            None => self.data.clone(),
+        };
+        let suffix: u32 = rand::random();
+        let file_name = format!("bhcli-{}-{}-{:08x}.{}", name, std::process::id(), suffix, self.format);
+        let path = std::env::temp_dir().join(file_name);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&path)?;
+        let file_path = TempFile(path);
+        file.write_all(&data)?;
+        Ok(file_path)
+    }
+}
+
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[derive(Debug)]
+pub enum SolverErr {
+    Io(io::Error),
+    Image(image::ImageError),
+    Terminal(viuer::ViuError),
+    /// Command line and how it exited
+    Command(String, ExitStatus),
+}
+
+impl From<io::Error> for SolverErr {
+    fn from(value: io::Error) -> Self {
+        SolverErr::Io(value)
+    }
+}
+
+impl From<image::ImageError> for SolverErr {
+    fn from(value: image::ImageError) -> Self {
+        SolverErr::Image(value)
+    }
+}
+
+impl Display for SolverErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SolverErr::Io(e) => write!(f, "{}", e),
+            SolverErr::Image(e) => write!(f, "{}", e),
+            SolverErr::Terminal(e) => write!(f, "{}", e),
+            SolverErr::Command(cmd, status) => write!(f, "`{}` failed: {}", cmd, status),
+        }
+    }
+}
+
+impl error::Error for SolverErr {}
+
+/// One way of answering the captcha, `login` tries the solvers of the chain in order.
+pub trait CaptchaSolver: Send {
+    /// None lets the next solver try.
+    fn solve(&self, captcha: &Captcha) -> Result<Option<String>, SolverErr>;
+
+    /// Needs someone to type the answer.
+    fn is_interactive(&self) -> bool {
+        false
+    }
+}
+
+/// Offline solver of the difficulty 1 and 2 captchas.
+pub struct Auto;
+
+impl CaptchaSolver for Auto {
+    fn solve(&self, captcha: &Captcha) -> Result<Option<String>, SolverErr> {
+        Ok(captcha::solve(&captcha.img))
+    }
+}
+
+/// Draws the captcha in the terminal with viuer (kitty, iTerm, sixel or half blocks) and asks for it.
+pub struct Terminal;
+
+impl CaptchaSolver for Terminal {
+    fn solve(&self, captcha: &Captcha) -> Result<Option<String>, SolverErr> {
+        // Biggest whole scale that fits, a half block is two pixels high
+        let (cols, rows) = crossterm::terminal::size().unwrap_or((80, 24));
+        let (width, height) = captcha.img.dimensions();
+        let scale = (cols as u32 / width)
+            .min(2 * (rows as u32).saturating_sub(2) / height)
+            .max(1);
+        let config = viuer::Config {
+            absolute_offset: false,
+            width: Some(width * scale),
+            height: Some((height * scale).div_ceil(2)),
+            ..Default::default()
+        };
+        println!("Captcha:");
+        viuer::print(&captcha.img, &config).map_err(SolverErr::Terminal)?;
+        read_answer("Captcha: ")
+    }
+
+    fn is_interactive(&self) -> bool {
+        true
+    }
+}
+
+/// Captcha as ascii art in the terminal, works everywhere.
+pub struct Prompt;
+
+impl CaptchaSolver for Prompt {
+    fn solve(&self, captcha: &Captcha) -> Result<Option<String>, SolverErr> {
+        println!("Captcha:");
+        println!("{}", super::image_to_ascii(&captcha.img, 80, 40));
+        read_answer("Captcha: ")
+    }
+
+    fn is_interactive(&self) -> bool {
+        true
+    }
+}
+
+/// Opens the captcha with an image viewer (`sxiv` by default) while asking for it.
+pub struct Viewer(pub String);
+
+/// Viewer showing the captcha, closed when dropped.
+pub struct OpenViewer {
+    child: Child,
+    _file: TempFile,
+}
+
+impl Drop for OpenViewer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Viewer {
+    pub fn open(&self, captcha: &Captcha) -> Result<OpenViewer, SolverErr> {
+        let (width, height) = captcha.img.dimensions();
+        let big = captcha.img.resize(
+            width * VIEWER_SCALE,
+            height * VIEWER_SCALE,
+            image::imageops::FilterType::Nearest,
+        );
+        let file = captcha.save("captcha-view", Some(&big))?;
+        let child = spawn(&self.0, &file, Stdio::null())?;
+        Ok(OpenViewer { child, _file: file })
+    }
+}
+
+impl CaptchaSolver for Viewer {
+    fn solve(&self, captcha: &Captcha) -> Result<Option<String>, SolverErr> {
+        let _viewer = self.open(captcha)?;
+        read_answer("Captcha: ")
+    }
+
+    fn is_interactive(&self) -> bool {
+        true
+    }
+}
+
+/// Runs a command with the path of the captcha image as last argument,
+/// the first line it prints is the answer.
+pub struct External(pub String);
+
+impl CaptchaSolver for External {
+    fn solve(&self, captcha: &Captcha) -> Result<Option<String>, SolverErr> {
+        let file = captcha.save("captcha", None)?;
+        let output = spawn(&self.0, &file, Stdio::piped())?.wait_with_output()?;
+        if !output.status.success() {
+            return Err(SolverErr::Command(self.0.clone(), output.status));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        Ok(stdout.lines().next().map(|l| l.trim().to_owned()).filter(|l| !l.is_empty()))
+    }
+}
+
+fn spawn(cmd: &str, file: &TempFile, stdout: Stdio) -> Result<Child, SolverErr> {
+    let mut args = cmd.split_whitespace();
+    let program = args.next().unwrap_or_default();
+    Ok(Command::new(program)
+        .args(args)
+        .arg(&file.0)
+        .stdout(stdout)
+        .stderr(Stdio::null())
+        .spawn()?)
+}
+
+// An empty answer lets the next solver try
+fn read_answer(prompt: &str) -> Result<Option<String>, SolverErr> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    trim_newline(&mut answer);
+    Ok(Some(answer.trim().to_owned()).filter(|a| !a.is_empty()))
+}
+
+/// Entry of a solver chain, `auto`, `terminal`, `prompt`, `viewer[:command]` or `command:command`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SolverConfig {
+    Auto,
+    Terminal,
+    Prompt,
+    Viewer(String),
+    Command(String),
+}
+
+impl FromStr for SolverConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, cmd) = match s.split_once(':') {
+            Some((kind, cmd)) => (kind.trim(), Some(cmd.trim().to_owned()).filter(|c| !c.is_empty())),
+            None => (s.trim(), None),
+        };
+        match (kind, cmd) {
+            ("auto", None) => Ok(SolverConfig::Auto),
+            ("terminal", None) => Ok(SolverConfig::Terminal),
+            ("prompt", None) => Ok(SolverConfig::Prompt),
+            ("viewer", cmd) => Ok(SolverConfig::Viewer(cmd.unwrap_or(DEFAULT_VIEWER.to_owned()))),
+            ("command", Some(cmd)) => Ok(SolverConfig::Command(cmd)),
+            ("command", None) => Err("`command` needs the command to run, e.g. `command:./solve.sh`".to_owned()),
+            _ => Err(format!(
+                "unknown captcha solver `{}`, expected auto, terminal, prompt, viewer[:command] or command:command",
+                s
+            )),
+        }
+    }
+}
+
+impl SolverConfig {
+    pub fn solver(&self) -> Box<dyn CaptchaSolver> {
+        match self {
+            SolverConfig::Auto => Box::new(Auto),
+            SolverConfig::Terminal => Box::new(Terminal),
+            SolverConfig::Prompt => Box::new(Prompt),
+            SolverConfig::Viewer(cmd) => Box::new(Viewer(cmd.clone())),
+            SolverConfig::Command(cmd) => Box::new(External(cmd.clone())),
+        }
+    }
+}
+
+/// Solvers of `cfgs`, in the same order.
+pub fn chain(cfgs: &[SolverConfig]) -> Vec<Box<dyn CaptchaSolver>> {
+    cfgs.iter().map(SolverConfig::solver).collect()
+}
+
+/// First answer of the chain, the solvers that fail are logged and skipped.
+pub fn solve(solvers: &[Box<dyn CaptchaSolver>], captcha: &Captcha) -> Option<String> {
+    solvers.iter().find_map(|solver| match solver.solve(captcha) {
+        Ok(answer) => answer,
+        Err(err) => {
+            log::error!("captcha solver failed: {}", err);
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn corpus_captcha() -> Captcha {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/captchas/difficulty1/000-DXyJn.gif");
+        let b64 = general_purpose::STANDARD.encode(fs::read(path).unwrap());
+        Captcha::from_data_url(&format!("data:image/gif;base64,{}", b64)).unwrap()
+    }
+
+    #[test]
+    fn parse_configs() {
+        let parse = |s: &str| s.parse::<SolverConfig>();
+        assert_eq!(parse("auto"), Ok(SolverConfig::Auto));
+        assert_eq!(parse("viewer"), Ok(SolverConfig::Viewer("sxiv".to_owned())));
+        assert_eq!(parse("viewer: feh -Z"), Ok(SolverConfig::Viewer("feh -Z".to_owned())));
+        assert_eq!(parse("command:./solve.sh --fast"), Ok(SolverConfig::Command("./solve.sh --fast".to_owned())));
+        assert!(parse("command").is_err());
+        assert!(parse("ocr").is_err());
+    }
+
+    #[test]
+    fn chain_falls_back() {
+        let captcha = corpus_captcha();
+        assert!(Captcha::from_data_url("data:image/jpeg;base64,AAAA").is_none());
+        // `false` fails and `true` prints nothing, neither answers
+        let solvers = chain(&[
+            SolverConfig::Command("false".to_owned()),
+            SolverConfig::Command("true".to_owned()),
+            SolverConfig::Auto,
+        ]);
+        assert_eq!(solve(&solvers, &captcha), Some("DXyJn".to_owned()));
+        // The image path is the last argument
+        let solvers = chain(&[SolverConfig::Command("basename -s .gif".to_owned())]);
+        let answer = solve(&solvers, &captcha).unwrap();
+        assert!(answer.starts_with("bhcli-captcha-"), "{}", answer);
+        assert_eq!(solve(&[], &captcha), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn temp_file_only_for_us() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let captcha = corpus_captcha();
+        let file = captcha.save("test", Some(&captcha.img)).unwrap();
+        assert_eq!(fs::metadata(&file.0).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(image::open(&file.0).unwrap().dimensions(), captcha.img.dimensions());
+        let path = file.0.clone();
+        drop(file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{Duration, NaiveDateTime};
+use serde_derive::Deserialize;
+
+use super::{Action, Verdict, Who};
+
+/// Flood and spam limits, `[flood]` in the rules file. A limit set to 0 is disabled.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FloodConfig {
+    pub who: Who,
+    /// What a violation leads to, warnings go up the escalation ladder.
+    pub action: Action,
+    pub weight: u32,
+    /// More than `max_messages` in `window_secs`.
+    pub window_secs: u64,
+    pub max_messages: usize,
+    /// The same text more than `max_duplicates` times in `duplicate_window_secs`.
+    pub duplicate_window_secs: u64,
+    pub max_duplicates: usize,
+    /// Messages of at least `caps_min_length` letters with more than this ratio of capitals.
+    pub max_caps_ratio: f64,
+    pub caps_min_length: usize,
+    /// Wall of text, in characters.
+    pub max_length: usize,
+    pub max_lines: usize,
+    /// `@nickname` mentions in one message.
+    pub max_mentions: usize,
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        FloodConfig {
+            who: Who::Guests,
+            action: Action::Warn,
+            weight: 1,
+            window_secs: 10,
+            max_messages: 5,
+            duplicate_window_secs: 60,
+            max_duplicates: 2,
+            max_caps_ratio: 0.7,
+            caps_min_length: 12,
+            max_length: 700,
+            max_lines: 15,
+            max_mentions: 5,
+        }
+    }
+}
+
+impl FloodConfig {
+    fn violation(&self, name: &str, reason: String) -> Verdict {
+        Verdict {
+            name: name.to_owned(),
+            action: self.action,
+            reason,
+            weight: self.weight,
+        }
+    }
+}
+
+/// Recent messages of every user, to detect floods over sliding windows.
+/// The windows use the dates of the messages, not when we got them, so that
+/// a batch fetched late is not taken for a flood.
+#[derive(Default)]
+pub struct FloodTracker {
+    users: HashMap<String, VecDeque<(NaiveDateTime, String)>>,
+}
+
+impl FloodTracker {
+    /// Record a message sent at `now` and return the limits it breaks.
+    pub fn check(&mut self, cfg: &FloodConfig, is_guest: bool, from: &str, msg: &str, now: NaiveDateTime) -> Vec<Verdict> {
+        let applies = match cfg.who {
+            Who::Guests => is_guest,
+            Who::Members => !is_guest,
+            Who::All => true,
+        };
+        if !applies {
+            return vec![];
+        }
+        let mut violations = vec![];
+
+        let keep = Duration::seconds(cfg.window_secs.max(cfg.duplicate_window_secs) as i64);
+        self.users.retain(|_, msgs| {
+            while msgs.front().is_some_and(|(at, _)| now - *at > keep) {
+                msgs.pop_front();
+            }
+            !msgs.is_empty()
+        });
+        let text = msg.trim().to_lowercase();
+        let msgs = self.users.entry(from.to_lowercase()).or_default();
+        msgs.push_back((now, text.clone()));
+
+        let within = |secs: u64| {
+            msgs.iter()
+                .filter(move |(at, _)| now - *at <= Duration::seconds(secs as i64))
+        };
+        let mut reset = false;
+        if cfg.max_messages > 0 && within(cfg.window_secs).count() > cfg.max_messages {
+            violations.push(cfg.violation(
+                "flood",
+                format!("Slow down, more than {} messages in {} seconds", cfg.max_messages, cfg.window_secs),
+            ));
+            reset = true;
+        }
+        if cfg.max_duplicates > 0
+            && within(cfg.duplicate_window_secs).filter(|(_, t)| *t == text).count() > cfg.max_duplicates
+        {
+            violations.push(cfg.violation("duplicate messages", "Stop repeating the same message".to_owned()));
+            reset = true;
+        }
+        // The next violation needs a new burst
+        if reset {
+            msgs.clear();
+        }
+
+        let letters: Vec<char> = msg.chars().filter(|c| c.is_alphabetic()).collect();
+        let caps = letters.iter().filter(|c| c.is_uppercase()).count();
+        if cfg.caps_min_length > 0
+            && letters.len() >= cfg.caps_min_length
+            && caps as f64 / letters.len() as f64 > cfg.max_caps_ratio
+        {
+            violations.push(cfg.violation("caps", "Stop shouting, too many capital letters".to_owned()));
+        }
+        if (cfg.max_length > 0 && msg.chars().count() > cfg.max_length)
+            || (cfg.max_lines > 0 && msg.lines().count() > cfg.max_lines)
+        {
+            violations.push(cfg.violation("wall of text", "No walls of text".to_owned()));
+        }
+        let mentions = msg.split_whitespace().filter(|w| w.len() > 1 && w.starts_with('@')).count();
+        if cfg.max_mentions > 0 && mentions > cfg.max_mentions {
+            violations.push(cfg.violation("mass mentions", format!("Do not mention more than {} users", cfg.max_mentions)));
+        }
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flood_checks() {
+        let cfg = FloodConfig::default();
+        let mut tracker = FloodTracker::default();
+        let start = NaiveDateTime::parse_from_str("2024-10-18 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let names = |v: Vec<Verdict>| v.into_iter().map(|v| v.name).collect::<Vec<_>>();
+
+        for i in 0..5 {
+            let at = start + Duration::seconds(i);
+            assert!(tracker.check(&cfg, true, "bob", &format!("hello {}", i), at).is_empty());
+        }
+        let at = start + Duration::seconds(5);
+        assert_eq!(names(tracker.check(&cfg, true, "Bob", "hello 5", at)), vec!["flood"]);
+        // The window was reset by the violation
+        assert!(tracker.check(&cfg, true, "bob", "hello 6", at).is_empty());
+        // Members are not checked by default
+        assert!(tracker.check(&cfg, false, "alice", &"@a ".repeat(10), at).is_empty());
+
+        let at = start + Duration::seconds(100);
+        assert!(tracker.check(&cfg, true, "carol", "buy now", at).is_empty());
+        assert!(tracker.check(&cfg, true, "carol", "Buy now ", at + Duration::seconds(20)).is_empty());
+        let v = tracker.check(&cfg, true, "carol", "buy now", at + Duration::seconds(40));
+        assert_eq!(names(v), vec!["duplicate messages"]);
+        // Out of the window
+        assert!(tracker.check(&cfg, true, "dave", "buy now", at).is_empty());
+        assert!(tracker.check(&cfg, true, "dave", "buy now", at + Duration::seconds(61)).is_empty());
+        assert!(tracker.check(&cfg, true, "dave", "buy now", at + Duration::seconds(122)).is_empty());
+
+        assert_eq!(names(tracker.check(&cfg, true, "erin", "WHY IS NOBODY ANSWERING", at)), vec!["caps"]);
+        assert!(tracker.check(&cfg, true, "erin", "OK LOL", at).is_empty());
+        assert_eq!(names(tracker.check(&cfg, true, "frank", &"a".repeat(701), at)), vec!["wall of text"]);
+        assert_eq!(
+            names(tracker.check(&cfg, true, "gina", "@a @b @c @d @e @f hi", at)),
+            vec!["mass mentions"]
+        );
+    }
+
+    #[test]
+    fn delayed_batch_is_not_a_flood() {
+        let cfg = FloodConfig::default();
+        let mut tracker = FloodTracker::default();
+        let start = NaiveDateTime::parse_from_str("2024-10-18 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        // Sent 3 seconds apart but fetched at once after a reconnect
+        for i in 0..10 {
+            let at = start + Duration::seconds(3 * i);
+            assert!(tracker.check(&cfg, true, "bob", &format!("hello {}", i), at).is_empty());
+        }
+    }
+}
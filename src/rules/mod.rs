@@ -0,0 +1,617 @@
+pub mod flood;
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use regex::{Regex, RegexBuilder};
+use serde_derive::Deserialize;
+
+/// Rules used when no rules file exists.
+pub const DEFAULT_RULES: &str = include_str!("default_rules.toml");
+
+#[derive(Debug)]
+pub enum RulesErr {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+    Yaml(serde_yaml::Error),
+    Regex(String, regex::Error),
+    UnknownList(String, String),
+    Escalation(String),
+}
+
+impl Display for RulesErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RulesErr::Io(e) => write!(f, "{}", e),
+            RulesErr::Toml(e) => write!(f, "{}", e),
+            RulesErr::Yaml(e) => write!(f, "{}", e),
+            RulesErr::Regex(rule, e) => write!(f, "rule '{}': {}", rule, e),
+            RulesErr::UnknownList(rule, list) => write!(f, "rule '{}': unknown list @{}", rule, list),
+            RulesErr::Escalation(e) => write!(f, "escalation: {}", e),
+        }
+    }
+}
+
+impl error::Error for RulesErr {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Who {
+    #[default]
+    Guests,
+    Members,
+    All,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    #[default]
+    Message,
+    Nickname,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Public warning, kicked once the warnings limit is reached.
+    Warn,
+    /// Public goodbye message then kick.
+    Kick,
+    /// Kick without public message, staff is told in the members channel.
+    SilentKick,
+    /// Only hide the message locally.
+    Hide,
+    /// Tell the staff channel, nothing visible to the user.
+    NotifyStaff,
+    /// Answer the user with the reason, used for help messages.
+    Reply,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Action::Warn => "warn",
+            Action::Kick => "kick",
+            Action::SilentKick => "silent kick",
+            Action::Hide => "hide",
+            Action::NotifyStaff => "notify staff",
+            Action::Reply => "reply",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Action {
+    /// Kicks first then warnings, a single one of them is applied per message.
+    pub fn priority(&self) -> u8 {
+        match self {
+            Action::SilentKick | Action::Kick => 0,
+            Action::Warn => 1,
+            _ => 2,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RulesConfig {
+    #[serde(default)]
+    lists: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+    #[serde(default)]
+    escalation: Escalation,
+    #[serde(default)]
+    flood: flood::FloodConfig,
+}
+
+/// What a rule or a flood check found about a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verdict {
+    pub name: String,
+    pub action: Action,
+    pub reason: String,
+    pub weight: u32,
+}
+
+/// What happens to a user as their warning points add up.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Escalation {
+    /// Warnings are forgotten once the user stayed this long without one.
+    #[serde(default = "default_quiet_minutes")]
+    pub quiet_minutes: u64,
+    pub ladder: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    /// Applies from this many warning points.
+    pub points: u32,
+    /// warn, kick or silent_kick
+    pub action: Action,
+    /// Kick again if the user comes back within this many minutes.
+    pub ban_minutes: Option<u64>,
+}
+
+// Below the first step of the ladder
+const PLAIN_WARN: Step = Step {
+    points: 0,
+    action: Action::Warn,
+    ban_minutes: None,
+};
+
+fn default_quiet_minutes() -> u64 {
+    30
+}
+
+impl Default for Escalation {
+    // Kicked on the second warning
+    fn default() -> Self {
+        Escalation {
+            quiet_minutes: default_quiet_minutes(),
+            ladder: vec![
+                Step { points: 1, action: Action::Warn, ban_minutes: None },
+                Step { points: 2, action: Action::Kick, ban_minutes: None },
+            ],
+        }
+    }
+}
+
+impl Escalation {
+    fn validate(mut self) -> Result<Self, RulesErr> {
+        if self.ladder.is_empty() {
+            return Err(RulesErr::Escalation("the ladder is empty".to_owned()));
+        }
+        if let Some(step) = self.ladder.iter().find(|s| s.action.priority() > 1) {
+            return Err(RulesErr::Escalation(format!(
+                "step at {} points: {} is not a warn or kick action",
+                step.points, step.action
+            )));
+        }
+        self.ladder.sort_by_key(|s| s.points);
+        Ok(self)
+    }
+
+    /// Step for a number of warning points, the last one reached, a plain warn before the first one.
+    pub fn step(&self, points: u32) -> &Step {
+        self.ladder
+            .iter()
+            .rev()
+            .find(|s| s.points <= points)
+            .unwrap_or(&PLAIN_WARN)
+    }
+
+    /// Points at which users get kicked, shown next to their warnings.
+    pub fn kick_points(&self) -> Option<u32> {
+        self.ladder.iter().find(|s| s.action != Action::Warn).map(|s| s.points)
+    }
+
+    pub fn quiet_secs(&self) -> i64 {
+        self.quiet_minutes as i64 * 60
+    }
+}
+
+#[derive(Deserialize)]
+struct RuleConfig {
+    name: String,
+    #[serde(default)]
+    who: Who,
+    #[serde(default)]
+    target: Target,
+    action: Action,
+    #[serde(default)]
+    reason: String,
+    #[serde(default)]
+    any: Vec<String>,
+    #[serde(default)]
+    all: Vec<Vec<String>>,
+    #[serde(default)]
+    regex: Vec<String>,
+    #[serde(default)]
+    exact: Vec<String>,
+    #[serde(default)]
+    unless: Vec<String>,
+    max_length: Option<usize>,
+    #[serde(default)]
+    normalize: bool,
+    #[serde(default)]
+    impersonates_member: bool,
+    #[serde(default = "default_weight")]
+    weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Debug)]
+pub struct Rule {
+    pub name: String,
+    pub who: Who,
+    pub target: Target,
+    pub action: Action,
+    reason: String,
+    any: Vec<String>,
+    all: Vec<Vec<String>>,
+    regex: Vec<Regex>,
+    exact: Vec<String>,
+    unless: Vec<String>,
+    max_length: Option<usize>,
+    normalize: bool,
+    impersonates_member: bool,
+    /// Warning points added by a warn rule.
+    pub weight: u32,
+}
+
+impl Rule {
+    fn from_config(cfg: RuleConfig, lists: &HashMap<String, Vec<String>>) -> Result<Self, RulesErr> {
+        let expand = |words: Vec<String>| -> Result<Vec<String>, RulesErr> {
+            let mut out = vec![];
+            for word in words {
+                match word.strip_prefix('@').filter(|name| is_list_name(name)) {
+                    Some(name) => out.extend(
+                        lists
+                            .get(name)
+                            .ok_or_else(|| RulesErr::UnknownList(cfg.name.clone(), name.to_owned()))?
+                            .iter()
+                            .map(|w| w.to_lowercase()),
+                    ),
+                    None => out.push(word.to_lowercase()),
+                }
+            }
+            Ok(out)
+        };
+        let regex = cfg
+            .regex
+            .iter()
+            .map(|r| {
+                RegexBuilder::new(r)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| RulesErr::Regex(cfg.name.clone(), e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Rule {
+            any: expand(cfg.any)?,
+            all: cfg.all.into_iter().map(expand).collect::<Result<_, _>>()?,
+            exact: expand(cfg.exact)?,
+            unless: expand(cfg.unless)?,
+            regex,
+            name: cfg.name,
+            who: cfg.who,
+            target: cfg.target,
+            action: cfg.action,
+            reason: cfg.reason,
+            max_length: cfg.max_length,
+            normalize: cfg.normalize,
+            impersonates_member: cfg.impersonates_member,
+            weight: cfg.weight,
+        })
+    }
+
+    /// Reason with `{user}` replaced by the nickname.
+    pub fn reason(&self, user: &str) -> String {
+        self.reason.replace("{user}", user)
+    }
+
+    pub fn verdict(&self, user: &str) -> Verdict {
+        Verdict {
+            name: self.name.clone(),
+            action: self.action,
+            reason: self.reason(user),
+            weight: self.weight,
+        }
+    }
+
+    fn applies_to(&self, is_guest: bool) -> bool {
+        match self.who {
+            Who::Guests => is_guest,
+            Who::Members => !is_guest,
+            Who::All => true,
+        }
+    }
+
+    fn has_conditions(&self) -> bool {
+        !self.any.is_empty()
+            || !self.all.is_empty()
+            || !self.regex.is_empty()
+            || !self.exact.is_empty()
+            || self.max_length.is_some()
+            || self.impersonates_member
+    }
+
+    fn matches(&self, text: &str, members: &[String]) -> bool {
+        if !self.has_conditions() {
+            return false;
+        }
+        let lower = text.to_lowercase();
+        let mut texts = vec![lower.as_str()];
+        let normalized = normalize(&lower);
+        if self.normalize && normalized != lower {
+            texts.push(&normalized);
+        }
+        let contains = |w: &String| texts.iter().any(|t| t.contains(w.as_str()));
+
+        if !self.any.is_empty() && !self.any.iter().any(contains) {
+            return false;
+        }
+        if !self.all.iter().all(|group| group.iter().any(contains)) {
+            return false;
+        }
+        if !self.regex.is_empty() && !self.regex.iter().any(|r| texts.iter().any(|t| r.is_match(t))) {
+            return false;
+        }
+        if !self.exact.is_empty() && !self.exact.iter().any(|e| texts.contains(&e.as_str())) {
+            return false;
+        }
+        if self.max_length.is_some_and(|max| lower.len() <= max) {
+            return false;
+        }
+        if self.impersonates_member
+            && !members
+                .iter()
+                .any(|m| m.len() >= 2 && lower.contains(&m.to_lowercase()))
+        {
+            return false;
+        }
+        !self.unless.iter().any(contains)
+    }
+}
+
+// `@intent` is a list, `@0 ` is a plain word
+fn is_list_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Undo the usual leetspeak substitutions.
+pub fn normalize(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '0' => 'o',
+            '1' | '!' | '|' => 'i',
+            '3' => 'e',
+            '4' | '@' => 'a',
+            '5' | '$' => 's',
+            '7' => 't',
+            '8' => 'b',
+            '9' => 'g',
+            c => c,
+        })
+        .collect()
+}
+
+#[derive(Debug, Default)]
+pub struct RuleSet {
+    pub rules: Vec<Rule>,
+    pub escalation: Escalation,
+    pub flood: flood::FloodConfig,
+}
+
+impl RuleSet {
+    pub fn from_toml(s: &str) -> Result<Self, RulesErr> {
+        Self::from_config(toml::from_str(s).map_err(RulesErr::Toml)?)
+    }
+
+    pub fn from_yaml(s: &str) -> Result<Self, RulesErr> {
+        Self::from_config(serde_yaml::from_str(s).map_err(RulesErr::Yaml)?)
+    }
+
+    /// Parse a rules file, YAML if the extension is `.yaml`/`.yml`, TOML otherwise.
+    pub fn load(path: &Path) -> Result<Self, RulesErr> {
+        let content = fs::read_to_string(path).map_err(RulesErr::Io)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => Self::from_yaml(&content),
+            _ => Self::from_toml(&content),
+        }
+    }
+
+    fn from_config(cfg: RulesConfig) -> Result<Self, RulesErr> {
+        let rules = cfg
+            .rules
+            .into_iter()
+            .map(|r| Rule::from_config(r, &cfg.lists))
+            .collect::<Result<_, _>>()?;
+        Ok(RuleSet {
+            rules,
+            escalation: cfg.escalation.validate()?,
+            flood: cfg.flood,
+        })
+    }
+
+    /// Every message rule matching, strongest action first.
+    pub fn check_message(&self, is_guest: bool, msg: &str) -> Vec<&Rule> {
+        let mut matches: Vec<&Rule> = self
+            .rules
+            .iter()
+            .filter(|r| r.target == Target::Message && r.applies_to(is_guest) && r.matches(msg, &[]))
+            .collect();
+        matches.sort_by_key(|r| r.action.priority());
+        matches
+    }
+
+    /// First nickname rule matching.
+    pub fn check_nickname(&self, is_guest: bool, nickname: &str, members: &[String]) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .find(|r| r.target == Target::Nickname && r.applies_to(is_guest) && r.matches(nickname, members))
+    }
+}
+
+/// The rule set in use and the file it comes from, reloaded when the file changes.
+pub struct RulesFile {
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    pub set: RuleSet,
+}
+
+impl RulesFile {
+    /// Use `path` if it exists, the built-in rules otherwise.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let mut rules = RulesFile {
+            path,
+            modified: None,
+            set: RuleSet::from_toml(DEFAULT_RULES).unwrap(),
+        };
+        rules.reload_if_changed();
+        rules
+    }
+
+    /// Rules files looked for next to the config file, in order. `rules.toml` when there is none
+    /// yet, it is loaded once it is created.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_path = confy::get_configuration_file_path("bhcli", None).ok()?;
+        let dir = config_path.parent()?;
+        let paths = ["rules.toml", "rules.yaml", "rules.yml"].map(|name| dir.join(name));
+        let existing = paths.iter().find(|p| p.exists()).cloned();
+        existing.or_else(|| paths.into_iter().next())
+    }
+
+    /// Reload the rules if the file was modified, keep the current ones if it is invalid.
+    pub fn reload_if_changed(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified.is_none() || modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+        match RuleSet::load(path) {
+            Ok(set) => {
+                log::info!("loaded {} rules from {:?}", set.rules.len(), path);
+                self.set = set;
+            }
+            Err(err) => log::error!("invalid rules file {:?}: {}", path, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_rules() {
+        let set = RuleSet::from_toml(DEFAULT_RULES).unwrap();
+        let check = |msg: &str| -> Vec<(&str, Action)> {
+            set.check_message(true, msg)
+                .iter()
+                .map(|r| (r.name.as_str(), r.action))
+                .collect()
+        };
+        assert_eq!(check("where can i do some betting"), vec![("betting", Action::Warn)]);
+        assert!(check("betting is boring").is_empty());
+        assert!(check("i have a cock.li cock account").is_empty());
+        assert_eq!(check("cp"), vec![("single bad word", Action::Kick)]);
+        assert_eq!(check("N1GG"), vec![("offensive terms", Action::Kick)]);
+        // Kicks are sorted before warnings
+        assert_eq!(check("need child porn")[0].1, Action::Kick);
+        assert_eq!(check(&"a".repeat(1001)), vec![("message too long", Action::Kick)]);
+        assert!(set.check_message(false, "cp").is_empty());
+
+        let members = vec!["dantca".to_owned()];
+        let nick = |n: &str| set.check_nickname(true, n, &members).map(|r| r.name.as_str());
+        assert_eq!(nick("DantcaFan"), Some("member imposter"));
+        assert_eq!(nick("h1tler"), Some("blacklisted names"));
+        assert_eq!(nick("xpldan"), Some("xpldan imposter"));
+        assert_eq!(nick("alice"), None);
+        assert_eq!(
+            set.check_nickname(true, "h1tler", &members).unwrap().reason("h1tler"),
+            "Do not use names on the blacklist 'h1tler' . ~Dantca bot"
+        );
+    }
+
+    #[test]
+    fn escalation_ladder() {
+        let set = RuleSet::from_toml(
+            "
+[escalation]
+quiet_minutes = 10
+ladder = [
+    { points = 3, action = 'kick', ban_minutes = 5 },
+    { points = 1, action = 'warn' },
+    { points = 5, action = 'silent_kick', ban_minutes = 1440 },
+]
+
+[[rules]]
+name = 'spam'
+any = ['spam']
+action = 'warn'
+weight = 2
+",
+        )
+        .unwrap();
+        let escalation = &set.escalation;
+        assert_eq!(escalation.quiet_secs(), 600);
+        assert_eq!(escalation.step(0).action, Action::Warn);
+        assert_eq!(escalation.step(2).action, Action::Warn);
+        assert_eq!(escalation.step(4).ban_minutes, Some(5));
+        assert_eq!(escalation.step(9).action, Action::SilentKick);
+        assert_eq!(escalation.kick_points(), Some(3));
+        assert_eq!(set.rules[0].weight, 2);
+
+        // Not kicked before the first step
+        let set = RuleSet::from_toml("[escalation]\nladder = [{ points = 3, action = 'kick', ban_minutes = 5 }]").unwrap();
+        assert_eq!(set.escalation.step(2), &Step { points: 0, action: Action::Warn, ban_minutes: None });
+        assert_eq!(set.escalation.step(3).action, Action::Kick);
+
+        assert_eq!(RuleSet::from_toml(DEFAULT_RULES).unwrap().escalation, Escalation::default());
+        assert_eq!(RuleSet::from_toml(DEFAULT_RULES).unwrap().flood, flood::FloodConfig::default());
+        assert!(matches!(
+            RuleSet::from_toml("[escalation]\nladder = [{ points = 1, action = 'hide' }]"),
+            Err(RulesErr::Escalation(_))
+        ));
+        assert!(matches!(
+            RuleSet::from_toml("[escalation]\nladder = []"),
+            Err(RulesErr::Escalation(_))
+        ));
+    }
+
+    #[test]
+    fn file_created_later_is_loaded() {
+        let path = std::env::temp_dir().join(format!("bhcli-rules-{}.toml", std::process::id()));
+        let mut rules = RulesFile::new(Some(path.clone()));
+        let builtin = rules.set.rules.len();
+        fs::write(&path, "[[rules]]\nname = 'x'\nany = ['spam']\naction = 'kick'").unwrap();
+        rules.reload_if_changed();
+        fs::remove_file(&path).unwrap();
+        assert_ne!(rules.set.rules.len(), builtin);
+        assert_eq!(rules.set.rules[0].name, "x");
+    }
+
+    #[test]
+    fn yaml_rules() {
+        let set = RuleSet::from_yaml(
+            "
+lists:
+  greet: [hello, hi]
+rules:
+  - name: greetings
+    who: all
+    any: ['@greet']
+    unless: [bye]
+    action: notify_staff
+    reason: '{user} says hi'
+",
+        )
+        .unwrap();
+        assert_eq!(set.check_message(false, "Hello there").len(), 1);
+        assert!(set.check_message(true, "hello and bye").is_empty());
+        assert_eq!(set.rules[0].reason("bob"), "bob says hi");
+
+        assert!(matches!(
+            RuleSet::from_yaml("rules: [{name: x, any: ['@nope'], action: kick}]"),
+            Err(RulesErr::UnknownList(_, _))
+        ));
+        assert!(matches!(
+            RuleSet::from_toml("[[rules]]\nname = 'x'\nregex = ['(']\naction = 'kick'"),
+            Err(RulesErr::Regex(_, _))
+        ));
+    }
+}
@@ -0,0 +1,120 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use bhcli::lechatphp::Session;
+use reqwest::blocking::Client;
+use reqwest::cookie::{CookieStore, Jar};
+use reqwest::Url;
+use serde_derive::{Deserialize, Serialize};
+
+/// The session of the last login, kept per profile so a restart does not need a new login and captcha.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedSession {
+    pub username: String,
+    pub id: String,
+    /// `Cookie` header the server got with the session
+    #[serde(default)]
+    pub cookies: String,
+    pub saved_at: i64,
+}
+
+/// `session-<profile>-<host>.json` next to the config file.
+pub fn default_path(url: &str, profile: &str) -> Option<PathBuf> {
+    crate::util::profile_file_path("session", url, profile, "json")
+}
+
+impl SavedSession {
+    pub fn capture(session: &Session, username: &str, jar: &Jar) -> Self {
+        let cookies = Url::parse(session.base_url())
+            .ok()
+            .and_then(|url| jar.cookies(&url))
+            .and_then(|header| header.to_str().ok().map(|s| s.to_owned()))
+            .unwrap_or_default();
+        Self {
+            username: username.to_owned(),
+            id: session.id().to_owned(),
+            cookies,
+            saved_at: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    /// None when there is no saved session.
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str(&s)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        // Anyone who can read the id is logged in as us
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(path)?.write_all(json.as_bytes())
+    }
+
+    pub fn remove(path: &Path) {
+        if let Err(err) = fs::remove_file(path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                log::error!("failed to remove the saved session {:?}: {}", path, err);
+            }
+        }
+    }
+
+    /// Put the cookies back in `jar`, which must be the one of `client`.
+    pub fn restore(&self, client: &Client, jar: &Jar, base_url: &str, page_php: &str) -> Session {
+        if let Ok(url) = Url::parse(base_url) {
+            for cookie in self.cookies.split("; ").filter(|c| !c.is_empty()) {
+                jar.add_cookie_str(cookie, &url);
+            }
+        }
+        Session::new(client, base_url, page_php, &self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_restore() {
+        let url = "http://chat.onion/index.php";
+        let jar = Jar::default();
+        jar.add_cookie_str("lang=en", &Url::parse(url).unwrap());
+        let client = Client::new();
+        let session = Session::new(&client, url, "chat.php", "abc123");
+        let saved = SavedSession::capture(&session, "dantca", &jar);
+        assert_eq!(saved.cookies, "lang=en");
+
+        let path = std::env::temp_dir().join(format!("bhcli-session-{}.json", std::process::id()));
+        assert_eq!(SavedSession::load(&path).unwrap(), None);
+        saved.save(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        let loaded = SavedSession::load(&path).unwrap().unwrap();
+        assert_eq!(loaded, saved);
+
+        let jar = Jar::default();
+        let restored = loaded.restore(&client, &jar, url, "chat.php");
+        assert_eq!(restored.id(), "abc123");
+        assert_eq!(jar.cookies(&Url::parse(url).unwrap()).unwrap(), "lang=en");
+        SavedSession::remove(&path);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,292 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bhcli::lechatphp::solvers::{Captcha, CaptchaSolver, SolverErr, Viewer};
+use bhcli::lechatphp::{Message, Users};
+use crossterm::event::{self, Event as CEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use image::{DynamicImage, GenericImageView};
+use tui::backend::CrosstermBackend;
+use tui::layout::Rect;
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{Block, Borders, Clear, Paragraph, Wrap};
+use tui::{Frame, Terminal};
+
+use crate::{draw_terminal_frame, App};
+
+pub type Tui = Terminal<CrosstermBackend<io::Stdout>>;
+
+/// The terminal of the TUI, kept while logged out so a relogin asks the captcha and the password
+/// in a dialog over the messages instead of leaving the alternate screen.
+pub struct LoginScreen {
+    terminal: Tui,
+    /// Last login status, shown in a dialog until the next login
+    pub status: Option<String>,
+    /// Behind the dialogs, App::default reads the config file
+    pub background: App,
+    users: Arc<Mutex<Users>>,
+}
+
+// What the dialog asks for
+struct Question<'a> {
+    title: &'a str,
+    img: Option<&'a DynamicImage>,
+    input: &'a str,
+    hidden: bool,
+}
+
+impl LoginScreen {
+    pub fn open() -> anyhow::Result<Arc<Mutex<Self>>> {
+        Ok(Arc::new(Mutex::new(Self {
+            terminal: crate::enter_tui()?,
+            status: None,
+            background: App::default(),
+            users: Arc::new(Mutex::new(Users::default())),
+        })))
+    }
+
+    /// Back to the normal screen, the status is printed there.
+    pub fn close(&mut self) -> anyhow::Result<()> {
+        crate::leave_tui(&mut self.terminal)?;
+        if let Some(status) = self.status.take() {
+            crate::print_status(&status);
+        }
+        Ok(())
+    }
+
+    pub fn terminal(&mut self) -> &mut Tui {
+        &mut self.terminal
+    }
+
+    /// The messages with the status over them.
+    pub fn draw(&mut self, messages: &Arc<Mutex<Vec<Message>>>, username: &str) -> io::Result<()> {
+        self.draw_question(messages, username, None)
+    }
+
+    fn draw_question(
+        &mut self,
+        messages: &Arc<Mutex<Vec<Message>>>,
+        username: &str,
+        question: Option<&Question>,
+    ) -> io::Result<()> {
+        let (app, users, status) = (&mut self.background, &self.users, self.status.as_deref());
+        self.terminal.draw(|f| {
+            draw_terminal_frame(f, app, messages, users, username);
+            match question {
+                Some(question) => draw_question(f, question, status),
+                None => {
+                    if let Some(status) = status {
+                        draw_status(f, status);
+                    }
+                }
+            }
+        })?;
+        Ok(())
+    }
+
+    /// Wait before the next login, true when `q`, `esc` or `ctrl+c` was pressed meanwhile.
+    pub fn wait(
+        &mut self,
+        duration: Duration,
+        messages: &Arc<Mutex<Vec<Message>>>,
+        username: &str,
+    ) -> anyhow::Result<bool> {
+        let until = Instant::now() + duration;
+        loop {
+            self.draw(messages, username)?;
+            let left = until.saturating_duration_since(Instant::now());
+            if !event::poll(left)? {
+                return Ok(false);
+            }
+            if let CEvent::Key(key) = event::read()? {
+                let quit = matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) || is_ctrl_c(&key);
+                if key.kind != KeyEventKind::Release && quit {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    /// Answer typed in a dialog, None when `esc` or `ctrl+c` was pressed or the answer is empty.
+    pub fn ask(
+        &mut self,
+        messages: &Arc<Mutex<Vec<Message>>>,
+        username: &str,
+        title: &str,
+        img: Option<&DynamicImage>,
+        hidden: bool,
+    ) -> anyhow::Result<Option<String>> {
+        let mut input = String::new();
+        loop {
+            let question = Question {
+                title,
+                img,
+                input: &input,
+                hidden,
+            };
+            self.draw_question(messages, username, Some(&question))?;
+            let CEvent::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind == KeyEventKind::Release {
+                continue;
+            }
+            match key.code {
+                KeyCode::Esc => return Ok(None),
+                _ if is_ctrl_c(&key) => return Ok(None),
+                KeyCode::Enter => {
+                    self.status = None;
+                    return Ok(Some(input.trim().to_owned()).filter(|a| !a.is_empty()));
+                }
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Char(c) => input.push(c),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn is_ctrl_c(key: &KeyEvent) -> bool {
+    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL)
+}
+
+fn draw_status(f: &mut Frame<CrosstermBackend<io::Stdout>>, status: &str) {
+    let mut lines: Vec<Spans> = status.lines().map(|l| Spans::from(l.to_owned())).collect();
+    lines.push(Spans::from(Span::styled("q to quit", Style::default().fg(Color::DarkGray))));
+    draw_dialog(f, "Login", lines);
+}
+
+fn draw_question(f: &mut Frame<CrosstermBackend<io::Stdout>>, question: &Question, status: Option<&str>) {
+    let size = f.size();
+    let mut lines = Vec::new();
+    if let Some(img) = question.img {
+        // Room left for the borders and the 4 lines below the captcha
+        let max_width = size.width.saturating_sub(4);
+        let max_height = size.height.saturating_sub(8);
+        lines.extend(half_blocks(img, max_width as u32, max_height as u32));
+        lines.push(Spans::default());
+    }
+    let input = if question.hidden {
+        "*".repeat(question.input.chars().count())
+    } else {
+        question.input.to_owned()
+    };
+    lines.push(Spans::from(vec![
+        Span::raw("> "),
+        Span::styled(input, Style::default().add_modifier(Modifier::BOLD)),
+        Span::styled("_", Style::default().add_modifier(Modifier::SLOW_BLINK)),
+    ]));
+    if let Some(status) = status {
+        lines.push(Spans::from(Span::styled(status.to_owned(), Style::default().fg(Color::Yellow))));
+    }
+    lines.push(Spans::from(Span::styled(
+        "enter to send, esc to skip",
+        Style::default().fg(Color::DarkGray),
+    )));
+    draw_dialog(f, question.title, lines);
+}
+
+// Centered and as small as the lines allow
+fn draw_dialog(f: &mut Frame<CrosstermBackend<io::Stdout>>, title: &str, lines: Vec<Spans>) {
+    let size = f.size();
+    let content_width = lines.iter().map(|l| l.width()).max().unwrap_or(0).max(title.len() + 2);
+    let width = (content_width as u16 + 4).max(40).min(size.width);
+    let height = (lines.len() as u16 + 2).min(size.height);
+    let area = Rect::new(
+        size.x + (size.width - width) / 2,
+        size.y + (size.height - height) / 2,
+        width,
+        height,
+    );
+    let paragraph = Paragraph::new(lines)
+        .block(Block::default().borders(Borders::ALL).title(title.to_owned()))
+        .wrap(Wrap { trim: false });
+    f.render_widget(Clear, area);
+    f.render_widget(paragraph, area);
+}
+
+/// The image with `▀`, each cell is two pixels, at the biggest whole scale that fits in
+/// `max_width` x `max_height` cells (shrunk when it does not fit at all).
+fn half_blocks(img: &DynamicImage, max_width: u32, max_height: u32) -> Vec<Spans<'static>> {
+    let (width, height) = img.dimensions();
+    let (max_width, max_height) = (max_width.max(1), 2 * max_height.max(1));
+    let scale = (max_width / width).min(max_height / height);
+    let img = if scale == 0 {
+        img.resize(max_width, max_height, image::imageops::FilterType::Nearest)
+    } else {
+        img.resize(width * scale, height * scale, image::imageops::FilterType::Nearest)
+    }
+    .to_rgb8();
+    let color = |x, y| {
+        let [r, g, b] = img.get_pixel(x, y).0;
+        Color::Rgb(r, g, b)
+    };
+    (0..img.height())
+        .step_by(2)
+        .map(|y| {
+            let spans: Vec<Span> = (0..img.width())
+                .map(|x| {
+                    let mut style = Style::default().fg(color(x, y));
+                    if y + 1 < img.height() {
+                        style = style.bg(color(x, y + 1));
+                    }
+                    Span::styled("▀", style)
+                })
+                .collect();
+            Spans::from(spans)
+        })
+        .collect()
+}
+
+/// Asks for the captcha in a dialog of the screen, with the image in it or in a viewer.
+pub struct ModalSolver {
+    pub screen: Arc<Mutex<LoginScreen>>,
+    pub messages: Arc<Mutex<Vec<Message>>>,
+    pub username: String,
+    pub viewer: Option<Viewer>,
+}
+
+impl CaptchaSolver for ModalSolver {
+    fn solve(&self, captcha: &Captcha) -> Result<Option<String>, SolverErr> {
+        let _viewer = self.viewer.as_ref().map(|viewer| viewer.open(captcha)).transpose()?;
+        let img = if self.viewer.is_some() { None } else { Some(&captcha.img) };
+        let mut screen = self.screen.lock().unwrap();
+        screen
+            .ask(&self.messages, &self.username, "Captcha", img, false)
+            .map_err(|err| SolverErr::Io(io::Error::other(err)))
+    }
+
+    fn is_interactive(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn half_blocks_fit() {
+        let mut img = RgbImage::new(2, 3);
+        img.put_pixel(0, 0, Rgb([255, 0, 0]));
+        img.put_pixel(0, 1, Rgb([0, 0, 255]));
+        let img = DynamicImage::ImageRgb8(img);
+
+        // Top pixel in front, bottom one behind, the last row has no bottom
+        let lines = half_blocks(&img, 2, 2);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].0[0].style, Style::default().fg(Color::Rgb(255, 0, 0)).bg(Color::Rgb(0, 0, 255)));
+        assert_eq!(lines[1].0[0].style, Style::default().fg(Color::Rgb(0, 0, 0)));
+
+        // 3 times bigger still fits, 4 times does not
+        let lines = half_blocks(&img, 7, 5);
+        assert_eq!((lines[0].0.len(), lines.len()), (6, 5));
+        // Too small, shrunk
+        let lines = half_blocks(&img, 1, 1);
+        assert_eq!(lines.len(), 1);
+    }
+}
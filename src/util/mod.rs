@@ -1,5 +1,7 @@
 pub mod event;
 
+use std::path::PathBuf;
+
 use tui::widgets::ListState;
 
 pub struct StatefulList<T> {
@@ -16,7 +18,7 @@ impl<T> StatefulList<T> {
     }
 
     pub fn next(&mut self) {
-        if self.items.len() == 0 {
+        if self.items.is_empty() {
             return;
         }
         let i = match self.state.selected() {
@@ -33,7 +35,7 @@ impl<T> StatefulList<T> {
     }
 
     pub fn previous(&mut self) {
-        if self.items.len() == 0 {
+        if self.items.is_empty() {
             return;
         }
         let i = match self.state.selected() {
@@ -60,3 +62,22 @@ impl<T> StatefulList<T> {
         self.state.select(Some(0));
     }
 }
+
+/// Database file `<kind>-<profile>-<host>.sqlite3` next to the config file.
+pub fn profile_db_path(kind: &str, url: &str, profile: &str) -> Option<PathBuf> {
+    profile_file_path(kind, url, profile, "sqlite3")
+}
+
+/// File `<kind>-<profile>-<host>.<ext>` next to the config file.
+pub fn profile_file_path(kind: &str, url: &str, profile: &str, ext: &str) -> Option<PathBuf> {
+    let config_path = confy::get_configuration_file_path("bhcli", None).ok()?;
+    let host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_owned()))
+        .unwrap_or_else(|| url.to_owned());
+    let name: String = format!("{}-{}-{}.{}", kind, profile, host, ext)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .collect();
+    Some(config_path.parent()?.join(name))
+}
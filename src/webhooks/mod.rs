@@ -0,0 +1,298 @@
+use std::collections::HashSet;
+use std::error;
+use std::fmt::{Display, Formatter};
+use std::thread;
+use std::time::Duration;
+
+use bhcli::lechatphp::{Message, MessageType, Users};
+use chrono::Utc;
+use crossbeam_channel::{Sender, TrySendError};
+use regex::Regex;
+use reqwest::blocking::Client;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Events waiting for delivery per webhook, more are dropped.
+const QUEUE_SIZE: usize = 100;
+const RETRIES: u32 = 3;
+const RETRY_SECONDS: u64 = 1;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// Any new message
+    Message,
+    /// Private message to us
+    Pm,
+    /// Message with `@our_nickname`
+    Mention,
+    Kick,
+    Join,
+    Leave,
+    /// The number of inbox messages changed
+    Inbox,
+}
+
+impl EventKind {
+    fn is_message(&self) -> bool {
+        matches!(self, EventKind::Message | EventKind::Pm | EventKind::Mention)
+    }
+}
+
+/// `[[webhooks]]` in the config file, the event is POSTed as json to `url`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Events sent to this url, all of them when empty
+    pub events: Vec<EventKind>,
+    /// Only messages from nicknames matching this regex
+    pub from: Option<String>,
+    /// Only messages whose text matches this regex
+    pub text: Option<String>,
+    /// Attempts after a failed delivery
+    pub retries: Option<u32>,
+    /// Wait before the first retry, doubled after every attempt
+    pub retry_seconds: Option<u64>,
+    /// e.g. `socks5h://127.0.0.1:9050` for an onion url, no proxy by default
+    pub proxy: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum WebhookErr {
+    Regex(String, regex::Error),
+    Http(String, reqwest::Error),
+}
+
+impl Display for WebhookErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebhookErr::Regex(url, e) => write!(f, "webhook {}: {}", url, e),
+            WebhookErr::Http(url, e) => write!(f, "webhook {}: {}", url, e),
+        }
+    }
+}
+
+impl error::Error for WebhookErr {}
+
+struct Hook {
+    url: String,
+    events: HashSet<EventKind>,
+    from: Option<Regex>,
+    text: Option<Regex>,
+    tx: Sender<Value>,
+}
+
+impl Hook {
+    fn new(cfg: &WebhookConfig) -> Result<Self, WebhookErr> {
+        let regex = |re: &Option<String>| {
+            re.as_deref()
+                .map(Regex::new)
+                .transpose()
+                .map_err(|e| WebhookErr::Regex(cfg.url.clone(), e))
+        };
+        let (from, text) = (regex(&cfg.from)?, regex(&cfg.text)?);
+        let http = |e| WebhookErr::Http(cfg.url.clone(), e);
+        let mut client = Client::builder().timeout(TIMEOUT);
+        if let Some(proxy) = &cfg.proxy {
+            client = client.proxy(reqwest::Proxy::all(proxy).map_err(http)?);
+        }
+        let client = client.build().map_err(http)?;
+
+        let (tx, rx) = crossbeam_channel::bounded::<Value>(QUEUE_SIZE);
+        let (url, retries) = (cfg.url.clone(), cfg.retries.unwrap_or(RETRIES));
+        let retry_delay = Duration::from_secs(cfg.retry_seconds.unwrap_or(RETRY_SECONDS));
+        thread::spawn(move || {
+            for payload in rx {
+                if let Err(err) = deliver(&client, &url, &payload, retries, retry_delay) {
+                    log::error!("webhook {}: {} event lost: {}", url, payload["event"], err);
+                }
+            }
+        });
+        Ok(Self {
+            url: cfg.url.clone(),
+            events: cfg.events.iter().copied().collect(),
+            from,
+            text,
+            tx,
+        })
+    }
+
+    fn wants(&self, kind: EventKind, data: &Value) -> bool {
+        let matches = |re: &Option<Regex>, field: &Value| {
+            re.as_ref().is_none_or(|re| re.is_match(field.as_str().unwrap_or_default()))
+        };
+        (self.events.is_empty() || self.events.contains(&kind))
+            && (!kind.is_message() || (matches(&self.from, &data["from"]) && matches(&self.text, &data["text"])))
+    }
+}
+
+fn deliver(client: &Client, url: &str, payload: &Value, retries: u32, mut delay: Duration) -> Result<(), String> {
+    let mut attempt = 0;
+    loop {
+        let err = match client.post(url).json(payload).send() {
+            Ok(resp) if resp.status().is_success() => return Ok(()),
+            Ok(resp) => format!("status {}", resp.status()),
+            Err(err) => err.to_string(),
+        };
+        if attempt == retries {
+            return Err(err);
+        }
+        log::warn!("webhook {}: {}, retry in {:?}", url, err, delay);
+        thread::sleep(delay);
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// Outgoing webhooks, and what is needed to tell what changed between two refreshes.
+#[derive(Default)]
+pub struct Webhooks {
+    hooks: Vec<Hook>,
+    users: Option<HashSet<String>>,
+    inbox: Option<usize>,
+}
+
+impl Webhooks {
+    pub fn new(cfgs: &[WebhookConfig]) -> Result<Self, WebhookErr> {
+        let hooks = cfgs.iter().map(Hook::new).collect::<Result<_, _>>()?;
+        Ok(Self {
+            hooks,
+            ..Default::default()
+        })
+    }
+
+    pub fn fire(&self, kind: EventKind, data: Value) {
+        let payload = json!({"event": kind, "at": Utc::now().timestamp(), "data": data});
+        for hook in self.hooks.iter().filter(|h| h.wants(kind, &payload["data"])) {
+            if let Err(TrySendError::Full(_)) = hook.tx.try_send(payload.clone()) {
+                log::warn!("webhook {}: too many events waiting, {} dropped", hook.url, payload["event"]);
+            }
+        }
+    }
+
+    /// A new message, also a pm, mention or kick depending on what it is.
+    pub fn message(&self, msg: &Message, members_tag: &str, username: &str) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        let data = crate::api::message_json(msg, members_tag);
+        let text = data["text"].as_str().unwrap_or_default();
+        if msg.typ == MessageType::SysMsg {
+            if let Some(at) = text.find(" has been kicked") {
+                self.fire(EventKind::Kick, json!({"user": &text[..at], "date": msg.date}));
+            }
+        } else if data["from"] != username {
+            if data["to"] == username {
+                self.fire(EventKind::Pm, data.clone());
+            }
+            if text.contains(&format!("@{}", username)) {
+                self.fire(EventKind::Mention, data.clone());
+            }
+        }
+        self.fire(EventKind::Message, data);
+    }
+
+    /// Joins and leaves since the last list, nothing for the first one.
+    pub fn users(&mut self, users: &Users) {
+        let online: HashSet<String> = [&users.admin, &users.staff, &users.members, &users.guests]
+            .into_iter()
+            .flat_map(|list| list.iter().map(|(_, name)| name.clone()))
+            .collect();
+        if let Some(last) = &self.users {
+            for user in online.difference(last) {
+                self.fire(EventKind::Join, json!({"user": user}));
+            }
+            for user in last.difference(&online) {
+                self.fire(EventKind::Leave, json!({"user": user}));
+            }
+        }
+        self.users = Some(online);
+    }
+
+    pub fn inbox(&mut self, count: usize) {
+        if let Some(last) = self.inbox.filter(|last| *last != count) {
+            self.fire(EventKind::Inbox, json!({"count": count, "previous": last}));
+        }
+        self.inbox = Some(count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bhcli::lechatphp::StyledText;
+
+    // Receives `n` requests, the first `failures` get a 500
+    fn receiver(n: usize, failures: usize) -> (String, thread::JoinHandle<Vec<Value>>) {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", server.server_addr().to_ip().unwrap());
+        let handle = thread::spawn(move || {
+            let mut received = vec![];
+            for i in 0..n {
+                let mut req = server.recv().unwrap();
+                let mut body = String::new();
+                req.as_reader().read_to_string(&mut body).unwrap();
+                let status = if i < failures { 500 } else { 200 };
+                req.respond(tiny_http::Response::empty(status)).unwrap();
+                received.push(serde_json::from_str(&body).unwrap());
+            }
+            received
+        });
+        (url, handle)
+    }
+
+    fn message(typ: MessageType, text: &str) -> Message {
+        Message::new(None, typ, "01-01 10:00:00".to_owned(), None, StyledText::Text(text.to_owned()))
+    }
+
+    #[test]
+    fn events_are_filtered_and_retried() {
+        let (url, handle) = receiver(4, 1);
+        let cfg = WebhookConfig {
+            url,
+            events: vec![EventKind::Kick, EventKind::Join, EventKind::Leave, EventKind::Inbox],
+            retry_seconds: Some(0),
+            ..Default::default()
+        };
+        let mut hooks = Webhooks::new(&[cfg]).unwrap();
+        hooks.message(&message(MessageType::UserMsg, "hello"), "[M] ", "dantca");
+        hooks.message(&message(MessageType::SysMsg, "spammer has been kicked."), "[M] ", "dantca");
+        let users = |names: &[&str]| Users {
+            guests: names.iter().map(|n| (tui::style::Color::White, n.to_string())).collect(),
+            ..Default::default()
+        };
+        hooks.users(&users(&["alice", "bob"]));
+        hooks.users(&users(&["bob", "carol"]));
+        hooks.inbox(0);
+        hooks.inbox(0);
+        hooks.inbox(2);
+
+        let received = handle.join().unwrap();
+        // The kick was delivered twice, the first attempt failed
+        assert_eq!(received[0], received[1]);
+        assert_eq!(received[1]["event"], "kick");
+        assert_eq!(received[1]["data"]["user"], "spammer");
+        let mut rest: Vec<String> = received[2..].iter().map(|e| format!("{} {}", e["event"], e["data"])).collect();
+        rest.sort();
+        assert_eq!(rest, vec![r#""join" {"user":"carol"}"#, r#""leave" {"user":"alice"}"#]);
+    }
+
+    #[test]
+    fn message_filters() {
+        let hook = Hook::new(&WebhookConfig {
+            url: "http://127.0.0.1:1".to_owned(),
+            from: Some("^bob$".to_owned()),
+            text: Some("(?i)help".to_owned()),
+            ..Default::default()
+        })
+        .unwrap();
+        let msg = |from: &str, text: &str| json!({"from": from, "text": text});
+        assert!(hook.wants(EventKind::Pm, &msg("bob", "HELP me")));
+        assert!(!hook.wants(EventKind::Message, &msg("bobby", "help")));
+        assert!(!hook.wants(EventKind::Message, &msg("bob", "hi")));
+        assert!(!hook.wants(EventKind::Message, &json!({"from": null, "text": "help"})));
+        assert!(hook.wants(EventKind::Join, &json!({"user": "alice"})));
+    }
+}
@@ -0,0 +1,72 @@
+#!/usr/bin/env python3
+# Renders the corpus of tests/captcha.rs with libgd, the way le-chat-php draws its captchas.
+# The answer is in the file name: <difficulty>/<n>-<answer>.gif
+import ctypes
+import os
+import random
+
+CHARS = "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ"
+COUNT = {1: 100, 2: 300, 3: 10}
+
+gd = ctypes.CDLL("libgd.so.3")
+gd.gdImageCreateTrueColor.restype = ctypes.c_void_p
+gd.gdFontGetGiant.restype = ctypes.c_void_p
+gd.gdImageGifPtr.restype = ctypes.c_void_p
+for f in ("gdImageFill", "gdImageString", "gdImageLine", "gdImageSetPixel", "gdImageArc", "gdImageDestroy", "gdImageGifPtr"):
+    getattr(gd, f).argtypes = None
+
+
+def rgb(r, g, b):
+    return (r << 16) | (g << 8) | b
+
+
+def gif(im):
+    size = ctypes.c_int()
+    ptr = gd.gdImageGifPtr(ctypes.c_void_p(im), ctypes.byref(size))
+    data = ctypes.string_at(ptr, size.value)
+    gd.gdFree(ctypes.c_void_p(ptr))
+    gd.gdImageDestroy(ctypes.c_void_p(im))
+    return data
+
+
+def captcha(code, difficulty, rnd):
+    im = ctypes.c_void_p(gd.gdImageCreateTrueColor(*((55, 24) if difficulty < 3 else (150, 200))))
+    font = ctypes.c_void_p(gd.gdFontGetGiant())
+    white = rgb(255, 255, 255)
+    gd.gdImageFill(im, 0, 0, rgb(0, 0, 0))
+    if difficulty < 3:
+        gd.gdImageString(im, font, 5, 5, code.encode(), white)
+        if difficulty == 2:
+            for _ in range(2):
+                gd.gdImageLine(im, 0, rnd.randint(0, 24), 55, rnd.randint(0, 24), white)
+            for _ in range(100):
+                gd.gdImageSetPixel(im, rnd.randint(0, 55), rnd.randint(0, 24), white)
+        return gif(im.value)
+    pos = [(rnd.randint(10, 130), rnd.randint(10, 170)) for _ in code]
+    for c, (x, y) in zip(code, pos):
+        gd.gdImageString(im, font, x, y, c.encode(), white)
+    red = rgb(200, 0, 0)
+    gd.gdImageArc(im, pos[0][0] + 5, pos[0][1] + 8, 16, 16, 0, 360, red)
+    for (x1, y1), (x2, y2) in zip(pos, pos[1:]):
+        gd.gdImageLine(im, x1 + 5, y1 + 8, x2 + 5, y2 + 8, red)
+    for _ in range(5):
+        gd.gdImageLine(im, 0, rnd.randint(0, 200), 150, rnd.randint(0, 200), white)
+    for _ in range(1000):
+        gd.gdImageSetPixel(im, rnd.randint(0, 150), rnd.randint(0, 200), white)
+    return gif(im.value)
+
+
+def main():
+    rnd = random.Random(1337)
+    root = os.path.dirname(os.path.abspath(__file__))
+    for difficulty, count in COUNT.items():
+        out = os.path.join(root, "difficulty{}".format(difficulty))
+        os.makedirs(out, exist_ok=True)
+        for n in range(count):
+            code = "".join(rnd.choice(CHARS) for _ in range(5))
+            with open(os.path.join(out, "{:03}-{}.gif".format(n, code)), "wb") as f:
+                f.write(captcha(code, difficulty, rnd))
+
+
+if __name__ == "__main__":
+    main()
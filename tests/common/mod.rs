@@ -0,0 +1,721 @@
+// In-process fake le-chat-php server used by the integration tests.
+//
+// It only implements the subset of the protocol the client relies on: login
+// page (with optional captcha and waitroom), `action=view`, `action=post`,
+// `action=delete`, `action=admin`, `action=profile`, `action=inbox` and
+// `action=logout`. Every request is recorded so tests can assert on what the
+// client actually sent.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use base64::{engine::general_purpose, Engine as _};
+use tiny_http::{Header, Method, Response, Server};
+
+pub const PAGE_PHP: &str = "chat.php";
+/// Answer of the captcha of the login page, a difficulty 1 one of the corpus.
+pub const CAPTCHA_ANSWER: &str = "DXyJn";
+
+type HtmlResponse = Response<Cursor<Vec<u8>>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockMessage {
+    pub id: usize,
+    pub date: String,
+    pub from: String,
+    /// `sendto` value used to post it ("s *", "s ?", a nickname...)
+    pub to: String,
+    pub text: String,
+    pub system: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub method: String,
+    pub action: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl Recorded {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .rev()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub from: String,
+    pub filename: String,
+    pub content: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct ChatState {
+    /// Expected captcha answer, no captcha on the login page when `None`.
+    pub captcha: Option<String>,
+    /// Number of waitroom refreshes before a guest is admitted.
+    pub waitroom: usize,
+    /// Seconds between waitroom refreshes sent in the `Refresh` header.
+    pub waitroom_refresh: u64,
+    /// Moderators send the guests of the waitroom away.
+    pub deny_waitroom: bool,
+    /// Reply "502 Bad Gateway" to everything.
+    pub down: bool,
+    /// Answer logins with a page that has no chat frames.
+    pub no_frames: bool,
+    /// Registered nicknames and their password.
+    pub registered: HashMap<String, String>,
+    pub admins: Vec<String>,
+    pub staff: Vec<String>,
+    pub kicked: Vec<String>,
+    pub messages: Vec<MockMessage>,
+    pub inbox: Vec<MockMessage>,
+    pub uploads: Vec<Upload>,
+    pub colors: HashMap<String, String>,
+    pub ignored: HashMap<String, Vec<String>>,
+    pub requests: Vec<Recorded>,
+    sessions: HashMap<String, String>,
+    waiting: HashMap<String, (String, usize)>,
+    online: Vec<String>,
+    next_id: usize,
+    next_session: usize,
+}
+
+impl ChatState {
+    pub fn register(&mut self, nick: &str, pass: &str) {
+        self.registered.insert(nick.to_owned(), pass.to_owned());
+    }
+
+    pub fn online(&self) -> &[String] {
+        &self.online
+    }
+
+    /// Forget every session, like a server restart.
+    pub fn expire_sessions(&mut self) {
+        self.sessions.clear();
+        self.online.clear();
+    }
+
+    pub fn add_message(&mut self, from: &str, to: &str, text: &str) -> usize {
+        self.next_id += 1;
+        let msg = MockMessage {
+            id: self.next_id,
+            date: format!("10-18 12:{:02}:{:02}", self.next_id / 60, self.next_id % 60),
+            from: from.to_owned(),
+            to: to.to_owned(),
+            text: text.to_owned(),
+            system: false,
+        };
+        self.messages.push(msg);
+        self.next_id
+    }
+
+    pub fn add_inbox(&mut self, from: &str, to: &str, text: &str) {
+        self.next_id += 1;
+        self.inbox.push(MockMessage {
+            id: self.next_id,
+            date: format!("10-18 11:{:02}:{:02}", self.next_id / 60, self.next_id % 60),
+            from: from.to_owned(),
+            to: to.to_owned(),
+            text: text.to_owned(),
+            system: false,
+        });
+    }
+
+    fn add_sysmsg(&mut self, text: String) {
+        self.next_id += 1;
+        self.messages.push(MockMessage {
+            id: self.next_id,
+            date: format!("10-18 12:{:02}:{:02}", self.next_id / 60, self.next_id % 60),
+            from: String::new(),
+            to: "s *".to_owned(),
+            text,
+            system: true,
+        });
+    }
+
+    fn is_mod(&self, nick: &str) -> bool {
+        self.admins.iter().any(|n| n == nick) || self.staff.iter().any(|n| n == nick)
+    }
+
+    fn color(&self, nick: &str) -> String {
+        self.colors
+            .get(nick)
+            .cloned()
+            .unwrap_or_else(|| "#FFFFFF".to_owned())
+    }
+
+    fn new_session(&mut self) -> String {
+        self.next_session += 1;
+        format!("mocksession{:04}", self.next_session)
+    }
+
+    fn enter(&mut self, session: String, nick: String) {
+        self.online.push(nick.clone());
+        self.sessions.insert(session, nick.clone());
+        self.add_sysmsg(format!("{} has joined the chat.", nick));
+    }
+
+    fn leave(&mut self, nick: &str) {
+        self.online.retain(|n| n != nick);
+        self.sessions.retain(|_, n| n != nick);
+    }
+}
+
+pub struct MockServer {
+    server: Arc<Server>,
+    state: Arc<Mutex<ChatState>>,
+    handle: Option<thread::JoinHandle<()>>,
+    port: u16,
+}
+
+impl MockServer {
+    pub fn start() -> Self {
+        Self::start_with(|_| {})
+    }
+
+    /// Start a server after letting the caller tweak the initial state.
+    pub fn start_with<F: FnOnce(&mut ChatState)>(setup: F) -> Self {
+        let mut state = ChatState::default();
+        setup(&mut state);
+        let state = Arc::new(Mutex::new(state));
+        let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+        let port = server.server_addr().to_ip().unwrap().port();
+        let handle = {
+            let server = Arc::clone(&server);
+            let state = Arc::clone(&state);
+            thread::spawn(move || {
+                for mut req in server.incoming_requests() {
+                    let resp = handle_request(&mut req, &state);
+                    let _ = req.respond(resp);
+                }
+            })
+        };
+        MockServer {
+            server,
+            state,
+            handle: Some(handle),
+            port,
+        }
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, ChatState> {
+        self.state.lock().unwrap()
+    }
+
+    /// All recorded requests for the given `action` parameter.
+    pub fn requests(&self, action: &str) -> Vec<Recorded> {
+        self.state()
+            .requests
+            .iter()
+            .filter(|r| r.action == action)
+            .cloned()
+            .collect()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn handle_request(req: &mut tiny_http::Request, state: &Mutex<ChatState>) -> HtmlResponse {
+    let mut state = state.lock().unwrap();
+    if state.down {
+        return Response::from_string("Bad Gateway").with_status_code(502);
+    }
+
+    let mut params = parse_query(req.url());
+    let mut body = Vec::new();
+    let _ = req.as_reader().read_to_end(&mut body);
+    let content_type = req
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Content-Type"))
+        .map(|h| h.value.as_str().to_owned())
+        .unwrap_or_default();
+    let mut files = Vec::new();
+    if let Some(boundary) = content_type.split("boundary=").nth(1) {
+        let (fields, f) = parse_multipart(&body, boundary);
+        params.extend(fields);
+        files = f;
+    } else {
+        params.extend(
+            url::form_urlencoded::parse(&body)
+                .map(|(k, v)| (k.into_owned(), v.into_owned())),
+        );
+    }
+
+    // Like PHP, the last occurrence of a repeated field wins
+    let get = |name: &str| -> Option<String> {
+        params
+            .iter()
+            .rev()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.clone())
+    };
+    let action = get("action").unwrap_or_default();
+    let method = if *req.method() == Method::Post {
+        "POST"
+    } else {
+        "GET"
+    };
+    state.requests.push(Recorded {
+        method: method.to_owned(),
+        action: action.clone(),
+        params: params.clone(),
+    });
+
+    if action.is_empty() {
+        return html(login_page(&state));
+    }
+    if action == "login" {
+        return login(&mut state, &params);
+    }
+    if action == "wait" {
+        return wait(&mut state, &get("session").unwrap_or_default());
+    }
+
+    let nick = match get("session").and_then(|s| state.sessions.get(&s).cloned()) {
+        Some(nick) => nick,
+        None => return html(error_page("Invalid/expired session")),
+    };
+
+    match (method, action.as_str()) {
+        ("GET", "post") => html(post_form()),
+        ("GET", "view") => html(view_page(&state, &nick)),
+        ("POST", "post") => {
+            let text = get("message").unwrap_or_default();
+            let sendto = get("sendto").unwrap_or_default();
+            if get("kick").is_some() {
+                if state.is_mod(&nick) {
+                    kick(&mut state, &sendto, get("what").as_deref() == Some("purge"));
+                }
+            } else if let Some((filename, content)) = files.into_iter().next() {
+                state.uploads.push(Upload {
+                    from: nick.clone(),
+                    filename,
+                    content,
+                });
+                state.add_message(&nick, &sendto, &text);
+            } else if !text.is_empty() {
+                state.add_message(&nick, &sendto, &text);
+            }
+            html(post_form())
+        }
+        ("POST", "delete") => {
+            match get("what").as_deref() {
+                Some("last") => {
+                    if let Some(pos) = state.messages.iter().rposition(|m| m.from == nick) {
+                        state.messages.remove(pos);
+                    }
+                }
+                Some("all") if get("confirm").as_deref() == Some("yes") => {
+                    state.messages.retain(|m| m.from != nick);
+                }
+                _ => {}
+            }
+            html(post_form())
+        }
+        ("POST", "admin") => {
+            if !state.is_mod(&nick) {
+                return html(error_page("Not a moderator"));
+            }
+            match get("what").as_deref() {
+                Some("choose") => html(admin_clean_page(&state)),
+                Some("selected") => {
+                    let ids = all_values(&params, "mid[]");
+                    state.messages.retain(|m| !ids.contains(&m.id.to_string()));
+                    html(admin_clean_page(&state))
+                }
+                _ => html(admin_clean_page(&state)),
+            }
+        }
+        ("POST", "profile") => {
+            if get("do").as_deref() == Some("save") {
+                if let Some(color) = get("colour") {
+                    state.colors.insert(nick.clone(), color);
+                }
+                if let Some(ignore) = get("ignore") {
+                    state.ignored.entry(nick.clone()).or_default().push(ignore);
+                }
+                if let Some(unignore) = get("unignore") {
+                    state
+                        .ignored
+                        .entry(nick.clone())
+                        .or_default()
+                        .retain(|n| n != &unignore);
+                }
+                if let Some(newnick) = get("newnickname") {
+                    for n in state.online.iter_mut().filter(|n| **n == nick) {
+                        *n = newnick.clone();
+                    }
+                    for n in state.sessions.values_mut().filter(|n| **n == nick) {
+                        *n = newnick.clone();
+                    }
+                }
+            }
+            html(profile_page())
+        }
+        (_, "inbox") => {
+            if get("do").as_deref() == Some("clean") && method == "POST" {
+                let ids = all_values(&params, "mid[]");
+                state
+                    .inbox
+                    .retain(|m| !(m.to == nick && ids.contains(&m.id.to_string())));
+            }
+            html(inbox_page(&state, &nick))
+        }
+        ("POST", "logout") => {
+            state.leave(&nick);
+            state.add_sysmsg(format!("{} has left the chat.", nick));
+            html(login_page(&state))
+        }
+        _ => html(error_page("Unknown action")),
+    }
+}
+
+fn login(state: &mut ChatState, params: &[(String, String)]) -> HtmlResponse {
+    let get = |name: &str| {
+        params
+            .iter()
+            .rev()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+            .unwrap_or("")
+    };
+    if let Some(expected) = state.captcha.clone() {
+        if get("challenge") != "mockchallenge" {
+            return html(error_page("Captcha already used or timed out"));
+        }
+        if get("captcha") != expected {
+            return html(error_page("Wrong Captcha"));
+        }
+    }
+    let nick = get("nick").to_owned();
+    if nick.is_empty() || nick.contains(' ') {
+        return html(error_page("Invalid nickname"));
+    }
+    if state.kicked.contains(&nick) {
+        return html(error_page("You have been kicked"));
+    }
+    let registered = match state.registered.get(&nick) {
+        Some(pass) if pass != get("pass") => {
+            return html(error_page("This nickname is a registered member"))
+        }
+        Some(_) => true,
+        None => false,
+    };
+    if !get("colour").is_empty() {
+        state.colors.insert(nick.clone(), get("colour").to_owned());
+    }
+    if state.no_frames {
+        return html("<html><body><p>Maintenance</p></body></html>".to_owned());
+    }
+    let session = state.new_session();
+    if !registered && state.waitroom > 0 {
+        let remaining = state.waitroom;
+        let resp = waitroom_response(&session, &nick, remaining, state.waitroom_refresh);
+        state.waiting.insert(session.clone(), (nick, remaining));
+        return resp;
+    }
+    state.enter(session.clone(), nick);
+    html(frameset(&session))
+}
+
+fn wait(state: &mut ChatState, session: &str) -> HtmlResponse {
+    let Some((nick, remaining)) = state.waiting.get(session).cloned() else {
+        return html(error_page("Invalid/expired session"));
+    };
+    if state.deny_waitroom {
+        state.waiting.remove(session);
+        return html(error_page("You have been kicked! Not today"));
+    }
+    if remaining > 1 {
+        let resp = waitroom_response(session, &nick, remaining - 1, state.waitroom_refresh);
+        state
+            .waiting
+            .insert(session.to_owned(), (nick, remaining - 1));
+        return resp;
+    }
+    state.waiting.remove(session);
+    state.enter(session.to_owned(), nick);
+    html(frameset(session))
+}
+
+fn kick(state: &mut ChatState, nick: &str, purge: bool) {
+    if !state.online.iter().any(|n| n == nick) {
+        return;
+    }
+    state.leave(nick);
+    state.kicked.push(nick.to_owned());
+    if purge {
+        state.messages.retain(|m| m.from != nick);
+    }
+    state.add_sysmsg(format!("{} has been kicked.", nick));
+}
+
+// Admitted after `remaining` refreshes, said as 10 seconds each like the real server would
+fn waitroom_response(session: &str, nick: &str, remaining: usize, refresh: u64) -> HtmlResponse {
+    let refresh = format!("{}; URL=/{}?action=wait&session={}&lang=en", refresh, PAGE_PHP, session);
+    html(format!(
+        "<html><body class=\"waitroom\"><h2>Waiting room</h2><p>Welcome {}, your login has been delayed, \
+         you can access the chat in {} seconds.</p></body></html>",
+        escape(nick),
+        remaining * 10
+    ))
+        .with_header(Header::from_bytes(&b"Refresh"[..], refresh.as_bytes()).unwrap())
+}
+
+fn html(body: String) -> HtmlResponse {
+    Response::from_string(body)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/html; charset=utf-8"[..]).unwrap())
+}
+
+fn error_page(msg: &str) -> String {
+    format!(
+        "<html><body class=\"error\"><h2>Error: {}</h2></body></html>",
+        escape(msg)
+    )
+}
+
+fn login_page(state: &ChatState) -> String {
+    let captcha = if state.captcha.is_some() {
+        format!(
+            "<tr><td>Copy: <img src=\"data:image/gif;base64,{}\"></td>\
+             <td><input type=\"hidden\" name=\"challenge\" value=\"mockchallenge\">\
+             <input type=\"text\" name=\"captcha\" autocomplete=\"off\"></td></tr>",
+            captcha_gif()
+        )
+    } else {
+        String::new()
+    };
+    format!(
+        "<html><body class=\"login\"><form action=\"{}\" method=\"post\">\
+         <input type=\"hidden\" name=\"action\" value=\"login\"><table>\
+         <tr><td>Nickname:</td><td><input type=\"text\" name=\"nick\"></td></tr>\
+         <tr><td>Password:</td><td><input type=\"password\" name=\"pass\"></td></tr>\
+         {}</table></form></body></html>",
+        PAGE_PHP, captcha
+    )
+}
+
+fn frameset(session: &str) -> String {
+    format!(
+        "<html><frameset rows=\"100,*,45\">\
+         <frame name=\"post\" src=\"{page}?action=post&session={s}&lang=en\">\
+         <frame name=\"view\" src=\"{page}?action=view&session={s}&lang=en\">\
+         <frame name=\"controls\" src=\"{page}?action=controls&session={s}&lang=en\">\
+         </frameset></html>",
+        page = PAGE_PHP,
+        s = session
+    )
+}
+
+fn post_form() -> String {
+    "<html><body class=\"post\"><form method=\"post\">\
+     <input type=\"hidden\" name=\"nc\" value=\"123456\">\
+     <input type=\"hidden\" name=\"postid\" value=\"abcdef\">\
+     <input type=\"text\" name=\"message\"></form></body></html>"
+        .to_owned()
+}
+
+fn profile_page() -> String {
+    "<html><body class=\"profile\"><form method=\"post\">\
+     <input type=\"checkbox\" name=\"bold\" id=\"bold\" checked>\
+     <input type=\"checkbox\" name=\"italic\" id=\"italic\">\
+     <input type=\"checkbox\" name=\"small\" id=\"small\">\
+     <select name=\"font\"><option value=\"\">Default</option>\
+     <option value=\"m\" selected>Monospace</option></select>\
+     </form></body></html>"
+        .to_owned()
+}
+
+fn user_span(state: &ChatState, nick: &str) -> String {
+    format!(
+        "<span style=\"color:{};\">{}</span>",
+        state.color(nick),
+        escape(nick)
+    )
+}
+
+fn message_div(state: &ChatState, m: &MockMessage, checkbox: bool) -> String {
+    let checkbox = if checkbox {
+        format!("<input type=\"checkbox\" name=\"mid[]\" value=\"{}\">", m.id)
+    } else {
+        String::new()
+    };
+    let body = if m.system {
+        format!("<span class=\"sysmsg\">{}</span>", escape(&m.text))
+    } else if m.to.starts_with("s ") {
+        format!(
+            "<span class=\"usermsg\">{} - <span style=\"color:{};\">{}</span></span>",
+            user_span(state, &m.from),
+            state.color(&m.from),
+            escape(&m.text)
+        )
+    } else {
+        format!(
+            "<span class=\"usermsg\">[{} to {}] - <span style=\"color:{};\">{}</span></span>",
+            user_span(state, &m.from),
+            user_span(state, &m.to),
+            state.color(&m.from),
+            escape(&m.text)
+        )
+    };
+    format!(
+        "<div class=\"msg\">{}<small>{} - </small>{}</div>",
+        checkbox, m.date, body
+    )
+}
+
+fn visible_to(m: &MockMessage, nick: &str) -> bool {
+    m.to.starts_with("s ") || m.from == nick || m.to == nick
+}
+
+fn view_page(state: &ChatState, nick: &str) -> String {
+    let inbox_count = state.inbox.iter().filter(|m| m.to == nick).count();
+    let notifications = if inbox_count > 0 {
+        format!(
+            "<div id=\"notifications\"><form action=\"{}\" method=\"post\">\
+             <input type=\"hidden\" name=\"action\" value=\"inbox\">\
+             <input type=\"submit\" value=\"Read {} messages\"></form></div>",
+            PAGE_PHP, inbox_count
+        )
+    } else {
+        String::new()
+    };
+    let messages: String = state
+        .messages
+        .iter()
+        .rev()
+        .filter(|m| visible_to(m, nick))
+        .map(|m| message_div(state, m, false))
+        .collect();
+    let rank = |n: &String| {
+        if state.admins.contains(n) {
+            0
+        } else if state.staff.contains(n) {
+            1
+        } else if state.registered.contains_key(n) {
+            2
+        } else {
+            3
+        }
+    };
+    let mut groups = vec![String::new(); 4];
+    for n in &state.online {
+        groups[rank(n)].push_str(&user_span(state, n));
+        groups[rank(n)].push(' ');
+    }
+    format!(
+        "<html><body class=\"messages\">{}<div id=\"messages\">{}</div>\
+         <div id=\"chatters\"><table><tr>\
+         <th>Admin:</th><td>{}</td><th>Staff:</th><td>{}</td>\
+         <th>Members:</th><td>{}</td><th>Guests:</th><td>{}</td>\
+         </tr></table></div></body></html>",
+        notifications, messages, groups[0], groups[1], groups[2], groups[3]
+    )
+}
+
+fn admin_clean_page(state: &ChatState) -> String {
+    let messages: String = state
+        .messages
+        .iter()
+        .rev()
+        .map(|m| message_div(state, m, true))
+        .collect();
+    format!(
+        "<html><body class=\"admin\"><form method=\"post\">\
+         <input type=\"hidden\" name=\"nc\" value=\"654321\">\
+         <div id=\"messages\">{}</div></form></body></html>",
+        messages
+    )
+}
+
+fn inbox_page(state: &ChatState, nick: &str) -> String {
+    let messages: String = state
+        .inbox
+        .iter()
+        .filter(|m| m.to == nick)
+        .map(|m| message_div(state, m, true))
+        .collect();
+    format!(
+        "<html><body class=\"inbox\"><form method=\"post\">{}</form></body></html>",
+        messages
+    )
+}
+
+fn all_values(params: &[(String, String)], name: &str) -> Vec<String> {
+    params
+        .iter()
+        .filter(|(k, _)| k == name)
+        .map(|(_, v)| v.clone())
+        .collect()
+}
+
+fn parse_query(url: &str) -> Vec<(String, String)> {
+    match url.split_once('?') {
+        Some((_, query)) => url::form_urlencoded::parse(query.as_bytes())
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect(),
+        None => vec![],
+    }
+}
+
+// Returns the text fields and the uploaded files (filename, content)
+type MultipartFields = (Vec<(String, String)>, Vec<(String, Vec<u8>)>);
+
+fn parse_multipart(body: &[u8], boundary: &str) -> MultipartFields {
+    let body = String::from_utf8_lossy(body);
+    let delimiter = format!("--{}", boundary.trim_matches('"'));
+    let mut fields = Vec::new();
+    let mut files = Vec::new();
+    for part in body.split(delimiter.as_str()) {
+        let Some((headers, content)) = part.split_once("\r\n\r\n") else {
+            continue;
+        };
+        let content = content.strip_suffix("\r\n").unwrap_or(content);
+        let attr = |name: &str| {
+            let key = format!("{}=\"", name);
+            let start = headers.find(&key)? + key.len();
+            let len = headers[start..].find('"')?;
+            Some(headers[start..start + len].to_owned())
+        };
+        let Some(name) = attr("name") else {
+            continue;
+        };
+        match attr("filename") {
+            Some(filename) => files.push((filename, content.as_bytes().to_vec())),
+            None => fields.push((name, content.to_owned())),
+        }
+    }
+    (fields, files)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn captcha_gif() -> String {
+    let path = format!("{}/tests/captchas/difficulty1/000-{}.gif", env!("CARGO_MANIFEST_DIR"), CAPTCHA_ANSWER);
+    general_purpose::STANDARD.encode(std::fs::read(path).unwrap())
+}
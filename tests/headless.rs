@@ -0,0 +1,109 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{MockServer, PAGE_PHP};
+
+// Poll `f` for at most 20 seconds
+fn wait_for<T>(mut f: impl FnMut() -> Option<T>) -> Option<T> {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(20) {
+        if let Some(v) = f() {
+            return Some(v);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    None
+}
+
+fn spawn_bot(server: &MockServer, home: &Path) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_DantcaBot"))
+        .args(["--headless", "--no-proxy", "--no-history", "--refresh-rate", "1"])
+        .args(["--url", &server.base_url(), "--page-php", PAGE_PHP, "-u", "dantca", "-p", "secret"])
+        .arg("--api-socket")
+        .arg(home.join("api.sock"))
+        .env("HOME", home)
+        .env("XDG_CONFIG_HOME", home.join(".config"))
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap()
+}
+
+#[test]
+fn headless_logs_out_on_sigterm() {
+    let server = MockServer::start_with(|s| s.register("dantca", "secret"));
+    let home = std::env::temp_dir().join(format!("bhcli-headless-{}", std::process::id()));
+    let mut bot = spawn_bot(&server, &home);
+
+    let online = wait_for(|| server.state().online().contains(&"dantca".to_owned()).then_some(()));
+    if online.is_none() {
+        let _ = bot.kill();
+        panic!("the bot never logged in");
+    }
+
+    // The control api posts like the TUI does
+    let mut api = UnixStream::connect(home.join("api.sock")).unwrap();
+    writeln!(api, r#"{{"id": 7, "method": "post", "params": {{"text": "hello from a script"}}}}"#).unwrap();
+    let mut response = String::new();
+    BufReader::new(api.try_clone().unwrap()).read_line(&mut response).unwrap();
+    assert_eq!(response.trim(), r#"{"id":7,"result":true}"#);
+    let posted = wait_for(|| {
+        let state = server.state();
+        state.messages.iter().any(|m| m.text == "hello from a script").then_some(())
+    });
+    assert!(posted.is_some());
+    drop(api);
+
+    Command::new("kill").args(["-TERM", &bot.id().to_string()]).status().unwrap();
+    let status: Option<ExitStatus> = wait_for(|| bot.try_wait().unwrap());
+    if status.is_none() {
+        let _ = bot.kill();
+    }
+    assert!(status.is_some_and(|s| s.success()), "{:?}", status);
+    assert_eq!(server.requests("logout").len(), 1);
+    assert!(server.state().online().is_empty());
+
+    let mut stdout = String::new();
+    bot.stdout.take().unwrap().read_to_string(&mut stdout).unwrap();
+    let logs: Vec<serde_json::Value> = stdout.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    assert!(logs.iter().any(|l| l["message"] == "logged in as dantca"), "{}", stdout);
+    assert!(logs.iter().any(|l| l["message"] == "logging out"), "{}", stdout);
+    let _ = std::fs::remove_dir_all(home);
+}
+
+#[test]
+fn restart_resumes_the_saved_session() {
+    let server = MockServer::start_with(|s| s.register("dantca", "secret"));
+    let home = std::env::temp_dir().join(format!("bhcli-resume-{}", std::process::id()));
+    let mut bot = spawn_bot(&server, &home);
+    let online = wait_for(|| server.state().online().contains(&"dantca".to_owned()).then_some(()));
+    // Killed without logging out, like a crash
+    let _ = bot.kill();
+    let _ = bot.wait();
+    assert!(online.is_some(), "the bot never logged in");
+    assert_eq!(server.requests("login").len(), 1);
+
+    let views = server.requests("view").len();
+    let mut bot = spawn_bot(&server, &home);
+    let resumed = wait_for(|| (server.requests("view").len() > views + 1).then_some(()));
+    let _ = bot.kill();
+    let _ = bot.wait();
+    assert!(resumed.is_some(), "the bot never read the messages again");
+    assert_eq!(server.requests("login").len(), 1);
+
+    // Once the server forgot it, a new login replaces it
+    server.state().expire_sessions();
+    let mut bot = spawn_bot(&server, &home);
+    let relogin = wait_for(|| (server.requests("login").len() == 2).then_some(()));
+    let _ = bot.kill();
+    let _ = bot.wait();
+    assert!(relogin.is_some());
+    let _ = std::fs::remove_dir_all(home);
+}
@@ -0,0 +1,296 @@
+mod common;
+
+use bhcli::lechatphp::{
+    self, get_message, Delete, LoginErr, MessageType, ProfileUpdate, Session, SessionErr, Waitroom,
+    SEND_TO_ALL,
+};
+use bhcli::lechatphp::solvers::{self, SolverConfig};
+use common::{MockServer, CAPTCHA_ANSWER, PAGE_PHP};
+use reqwest::blocking::Client;
+use std::time::Duration;
+
+fn client() -> Client {
+    Client::builder().cookie_store(true).build().unwrap()
+}
+
+fn login(server: &MockServer, nick: &str, pass: &str) -> Result<Session, LoginErr> {
+    lechatphp::login(
+        &client(),
+        &server.base_url(),
+        PAGE_PHP,
+        nick,
+        pass,
+        "#FF0000",
+        &solvers::chain(&[SolverConfig::Auto]),
+        &mut lechatphp::keep_waiting,
+    )
+}
+
+#[test]
+fn login_and_view() {
+    let server = MockServer::start();
+    let session = login(&server, "alice", "").unwrap();
+    assert!(session.id().starts_with("mocksession"));
+
+    let view = session.view().unwrap();
+    assert_eq!(view.users.guests.len(), 1);
+    assert_eq!(view.users.guests[0].1, "alice");
+    assert!(view.users.is_guest("alice"));
+    assert_eq!(view.last_joined.as_deref(), Some("alice"));
+    assert_eq!(view.messages[0].typ, MessageType::SysMsg);
+    assert_eq!(view.messages[0].text.text(), "alice has joined the chat.");
+    assert_eq!(view.inbox_count, None);
+}
+
+#[test]
+fn login_sends_captcha_challenge() {
+    let server = MockServer::start_with(|s| s.captcha = Some("zzzzz".to_owned()));
+    assert!(matches!(
+        login(&server, "alice", ""),
+        Err(LoginErr::CaptchaWgErr)
+    ));
+    let req = &server.requests("login")[0];
+    assert_eq!(req.param("challenge"), Some("mockchallenge"));
+    assert_eq!(req.param("captcha"), Some(CAPTCHA_ANSWER));
+}
+
+#[test]
+fn login_solves_captcha() {
+    let server = MockServer::start_with(|s| {
+        s.captcha = Some(CAPTCHA_ANSWER.to_owned());
+        s.register("bob", "secret");
+    });
+    assert!(login(&server, "bob", "secret").is_ok());
+    // Nothing is sent when no solver answers
+    assert!(matches!(
+        lechatphp::login(&client(), &server.base_url(), PAGE_PHP, "bob", "secret", "#FF0000", &[], &mut lechatphp::keep_waiting),
+        Err(LoginErr::CaptchaUnsolvedErr)
+    ));
+    assert_eq!(server.requests("login").len(), 1);
+}
+
+#[test]
+fn login_errors() {
+    let server = MockServer::start_with(|s| {
+        s.register("bob", "secret");
+        s.kicked.push("eve".to_owned());
+    });
+    assert!(matches!(login(&server, "bob", "nope"), Err(LoginErr::RegErr)));
+    assert!(matches!(login(&server, "eve", ""), Err(LoginErr::KickedErr)));
+    assert!(matches!(
+        login(&server, "bad nick", ""),
+        Err(LoginErr::NicknameErr)
+    ));
+    assert!(login(&server, "bob", "secret").is_ok());
+
+    let down = MockServer::start_with(|s| s.down = true);
+    assert!(matches!(login(&down, "alice", ""), Err(LoginErr::ServerDownErr)));
+
+    let no_frames = MockServer::start_with(|s| s.no_frames = true);
+    assert!(matches!(login(&no_frames, "alice", ""), Err(LoginErr::UnknownErr)));
+}
+
+#[test]
+fn login_through_waitroom() {
+    let server = MockServer::start_with(|s| s.waitroom = 2);
+    let mut states = Vec::new();
+    let session = lechatphp::login(
+        &client(),
+        &server.base_url(),
+        PAGE_PHP,
+        "alice",
+        "",
+        "#FF0000",
+        &[],
+        &mut |w: &Waitroom| {
+            states.push(w.clone());
+            true
+        },
+    )
+    .unwrap();
+    assert_eq!(server.requests("wait").len(), 2);
+    assert!(session.view().is_ok());
+
+    assert_eq!(states.len(), 2);
+    assert_eq!(states[0].refreshes, 0);
+    assert_eq!(states[0].refresh, Duration::ZERO);
+    assert_eq!(states[0].admission_in, Some(Duration::from_secs(20)));
+    assert!(states[0].notice.starts_with("Welcome alice"), "{}", states[0].notice);
+    assert_eq!(states[1].admission_in, Some(Duration::from_secs(10)));
+}
+
+#[test]
+fn waitroom_countdown() {
+    let server = MockServer::start_with(|s| {
+        s.waitroom = 1;
+        s.waitroom_refresh = 2;
+    });
+    let mut states = Vec::new();
+    lechatphp::login(&client(), &server.base_url(), PAGE_PHP, "alice", "", "#FF0000", &[], &mut |w| {
+        states.push(w.clone());
+        true
+    })
+    .unwrap();
+    // Told every second until the refresh asked by the server
+    let countdown: Vec<u64> = states.iter().map(|w| w.next_refresh.as_secs()).collect();
+    assert_eq!(countdown, vec![2, 1, 0]);
+    assert!(states.iter().all(|w| w.refresh == Duration::from_secs(2) && w.refreshes == 0));
+    assert_eq!(server.requests("wait").len(), 1);
+}
+
+#[test]
+fn leave_or_denied_in_waitroom() {
+    let server = MockServer::start_with(|s| s.waitroom = 2);
+    let cancelled = lechatphp::login(&client(), &server.base_url(), PAGE_PHP, "alice", "", "#FF0000", &[], &mut |_| false);
+    assert!(matches!(cancelled, Err(LoginErr::WaitroomCancelledErr)));
+    assert!(server.requests("wait").is_empty());
+
+    let server = MockServer::start_with(|s| {
+        s.waitroom = 2;
+        s.deny_waitroom = true;
+    });
+    assert!(matches!(login(&server, "alice", ""), Err(LoginErr::AdmissionDeniedErr)));
+    // Only the guests of the waitroom are denied
+    server.state().register("bob", "secret");
+    assert!(login(&server, "bob", "secret").is_ok());
+}
+
+#[test]
+fn post_public_and_private() {
+    let server = MockServer::start();
+    let alice = login(&server, "alice", "").unwrap();
+    let bob = login(&server, "bob", "").unwrap();
+
+    alice.post("hello world", None).unwrap();
+    bob.post("hi alice", Some("alice")).unwrap();
+
+    let post = &server.requests("post")[1];
+    assert_eq!(post.param("postid"), Some("abcdef"));
+    assert_eq!(post.param("nc"), Some("123456"));
+    assert_eq!(post.param("sendto"), Some(SEND_TO_ALL));
+
+    let view = alice.view().unwrap();
+    let (from, to, msg) = get_message(&view.messages[0].text, "[M]").unwrap();
+    assert_eq!((from.as_str(), to.as_deref(), msg.as_str()), ("bob", Some("alice"), "hi alice"));
+    let (from, to, msg) = get_message(&view.messages[1].text, "[M]").unwrap();
+    assert_eq!((from.as_str(), to, msg.as_str()), ("alice", None, "hello world"));
+}
+
+#[test]
+fn kick_user() {
+    let server = MockServer::start_with(|s| {
+        s.register("dan", "pass");
+        s.admins.push("dan".to_owned());
+    });
+    let dan = login(&server, "dan", "pass").unwrap();
+    let spammer = login(&server, "spammer", "").unwrap();
+    spammer.post("buy my stuff", None).unwrap();
+
+    dan.kick("spammer", "bye").unwrap();
+
+    let view = dan.view().unwrap();
+    assert_eq!(view.kicked_count, 1);
+    assert_eq!(view.users.admin[0].1, "dan");
+    assert!(!view.users.is_guest("spammer"));
+    assert!(view.messages.iter().all(|m| !m.text.text().contains("buy my stuff")));
+    assert!(matches!(spammer.view(), Err(SessionErr::Expired)));
+    assert!(matches!(login(&server, "spammer", ""), Err(LoginErr::KickedErr)));
+}
+
+#[test]
+fn delete_messages() {
+    let server = MockServer::start_with(|s| {
+        s.register("dan", "pass");
+        s.staff.push("dan".to_owned());
+    });
+    let dan = login(&server, "dan", "pass").unwrap();
+    let alice = login(&server, "alice", "").unwrap();
+    alice.post("first", None).unwrap();
+    alice.post("second", None).unwrap();
+    alice.post("third", None).unwrap();
+
+    alice.delete(Delete::Last).unwrap();
+    assert!(!server.state().messages.iter().any(|m| m.text == "third"));
+
+    let view = dan.view().unwrap();
+    let first = view
+        .messages
+        .iter()
+        .find(|m| m.text.text().ends_with("first"))
+        .unwrap();
+    dan.delete(Delete::Message(first.date.clone(), first.text.text()))
+        .unwrap();
+    let texts: Vec<_> = server.state().messages.iter().map(|m| m.text.clone()).collect();
+    assert!(!texts.contains(&"first".to_owned()));
+    assert!(texts.contains(&"second".to_owned()));
+
+    alice.delete(Delete::All).unwrap();
+    assert!(!server.state().messages.iter().any(|m| m.from == "alice"));
+}
+
+#[test]
+fn inbox() {
+    let server = MockServer::start_with(|s| {
+        s.add_inbox("bob", "alice", "are you there?");
+        s.add_inbox("carol", "alice", "ping");
+    });
+    let alice = login(&server, "alice", "").unwrap();
+    assert_eq!(alice.view().unwrap().inbox_count, Some(2));
+
+    let msgs = alice.inbox().unwrap();
+    assert_eq!(msgs.len(), 2);
+    assert_eq!(msgs[0].from, "bob");
+    assert_eq!(msgs[0].to, "alice");
+    assert_eq!(msgs[0].text, "are you there?");
+
+    alice.clean_inbox().unwrap();
+    assert!(server.state().inbox.is_empty());
+    assert_eq!(alice.view().unwrap().inbox_count, None);
+}
+
+#[test]
+fn update_profile() {
+    let server = MockServer::start();
+    let alice = login(&server, "alice", "").unwrap();
+    alice
+        .profile(ProfileUpdate {
+            color: Some("00FF00".to_owned()),
+            ignore: Some("bob".to_owned()),
+            ..Default::default()
+        })
+        .unwrap();
+    let req = server.requests("profile").pop().unwrap();
+    assert_eq!(req.param("do"), Some("save"));
+    assert_eq!(req.param("bold"), Some("on"));
+    assert_eq!(req.param("italic"), None);
+    assert_eq!(req.param("font"), Some("m"));
+    let state = server.state();
+    assert_eq!(state.colors["alice"], "00FF00");
+    assert_eq!(state.ignored["alice"], vec!["bob".to_owned()]);
+}
+
+#[test]
+fn upload_file() {
+    let server = MockServer::start();
+    let alice = login(&server, "alice", "").unwrap();
+    let path = std::env::temp_dir().join(format!("bhcli-upload-{}.txt", std::process::id()));
+    std::fs::write(&path, "file content").unwrap();
+    alice
+        .upload(path.to_str().unwrap(), SEND_TO_ALL, "look at this")
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let state = server.state();
+    assert_eq!(state.uploads.len(), 1);
+    assert_eq!(state.uploads[0].content, b"file content");
+    assert_eq!(state.messages.last().unwrap().text, "look at this");
+}
+
+#[test]
+fn logout_expires_session() {
+    let server = MockServer::start();
+    let alice = login(&server, "alice", "").unwrap();
+    alice.logout().unwrap();
+    assert!(server.state().online().is_empty());
+    assert!(matches!(alice.view(), Err(SessionErr::Expired)));
+}